@@ -1,61 +1,239 @@
-use anyhow::Result;
-use chat::{serialize_message, Message};
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use chat::{codec::Codec, tls, Message, DEFAULT_ROOM};
+use clap::{Parser, ValueEnum};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines, Stdin},
-    join,
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpStream,
-    },
+    io::{self, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, Lines, Stdin},
+    net::TcpStream,
     task,
 };
+use tokio_rustls::rustls::pki_types::ServerName;
+
+type Reader = Box<dyn AsyncRead + Unpin + Send>;
+type Writer = Box<dyn AsyncWrite + Unpin + Send>;
+
+#[derive(Parser)]
+#[command(version, about = "A client for the chat server")]
+struct Args {
+    /// The server's address
+    #[arg(long, env = "CHAT_SERVER", default_value = "127.0.0.1:8000")]
+    server: String,
+    /// Enter the chat under this name instead of asking for it
+    #[arg(long, env = "CHAT_USER")]
+    user: Option<String>,
+    /// How to talk to the server; JSON is easier to debug
+    #[arg(long, env = "CHAT_CODEC", value_enum, default_value_t = Codec::Binary)]
+    codec: Codec,
+    /// How to print what the server sends
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+    /// Connect over TLS
+    #[arg(long, env = "CHAT_TLS")]
+    tls: bool,
+    /// Trust only the certificates in this PEM file, e.g. the one a server made with
+    /// --tls-self-signed, instead of the usual authorities. Implies --tls.
+    #[arg(long, env = "CHAT_TLS_CA")]
+    tls_ca: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// For people to read
+    Text,
+    /// One JSON message per line, and nothing else, for scripts
+    Json,
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let args = Args::parse();
+    let text = args.format == Format::Text;
     let stdin = tokio::io::stdin();
     let mut stdin_lines = BufReader::new(stdin).lines();
 
-    println!("Enter your username and press <enter>");
-    let mut username = stdin_lines.next_line().await?.unwrap();
+    let mut username = match args.user.clone() {
+        Some(user) => user,
+        None => {
+            eprintln!("Enter your username and press <enter>");
+            let user = stdin_lines.next_line().await?;
+            user.context("No username entered")?
+        }
+    };
     username.truncate(username.trim_end().len()); // Trim newline at the end.
     let username = Message::User(username);
-    println!("Connecting to server...");
-    let stream = TcpStream::connect("127.0.0.1:8000").await?;
-    let (tcp_read, mut tcp_write) = stream.into_split();
+    if text {
+        println!("Connecting to server...");
+    }
+    let (tcp_read, mut tcp_write) = connect(&args).await?;
 
-    tcp_write.write_all(&serialize_message(username)?).await?;
-    println!("Connected! You can now enter messages!");
+    // The codec is chosen in JSON, which every server understands.
+    let codec = args.codec;
+    let hello = Codec::Json.encode(&Message::Codec(codec))?;
+    tcp_write.write_all(&hello).await?;
+    tcp_write.write_all(&codec.encode(&username)?).await?;
+    if text {
+        println!("Connected! You can now enter messages!");
+        println!("Use /join <room>, /leave [room] and /rooms to move between rooms,");
+        println!(
+            "/msg <user> <message> to message someone privately, and /who to see who is online"
+        );
+    }
 
-    let chat_input_task = task::spawn(handle_chat_input(stdin_lines, tcp_write));
-    let incoming_chats_task = task::spawn(handle_incoming_chats(tcp_read));
-    let _ = join!(chat_input_task, incoming_chats_task);
-    Ok(())
+    task::spawn(handle_chat_input(stdin_lines, tcp_write, codec));
+    // The server closes the connection once we stop sending, so this always ends.
+    let _ = task::spawn(handle_incoming_chats(tcp_read, codec, args.format)).await;
+    if text {
+        println!("Disconnected");
+    }
+    // A pending read from stdin would keep the runtime from shutting down.
+    std::process::exit(0);
+}
+
+async fn connect(args: &Args) -> Result<(Reader, Writer)> {
+    let stream = TcpStream::connect(&args.server).await?;
+    if !args.tls && args.tls_ca.is_none() {
+        let (tcp_read, tcp_write) = stream.into_split();
+        return Ok((Box::new(tcp_read), Box::new(tcp_write)));
+    }
+    // The certificate must be for the host we asked for, without the port.
+    let host = args
+        .server
+        .rsplit_once(':')
+        .map_or(&*args.server, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let name = ServerName::try_from(host.to_string())?;
+    let connector = tls::connector(args.tls_ca.as_deref())?;
+    let stream = connector.connect(name, stream).await?;
+    let (tls_read, tls_write) = io::split(stream);
+    Ok((Box::new(tls_read), Box::new(tls_write)))
 }
 
+/// Sends what the user types to the server. Hints for the user go to stderr, to keep them
+/// out of the JSON output.
 async fn handle_chat_input(
     mut stdin: Lines<BufReader<Stdin>>,
-    mut tcp_write: OwnedWriteHalf,
+    mut tcp_write: Writer,
+    codec: Codec,
 ) -> Result<()> {
+    // Messages go to the room joined last
+    let mut current_room = Some(DEFAULT_ROOM.to_string());
     while let Some(line) = stdin.next_line().await? {
-        let msg = Message::ClientMessage(line);
-        tcp_write.write_all(&serialize_message(msg)?).await?;
+        let (command, arg) = line.split_once(' ').unwrap_or((&line, ""));
+        let arg = arg.trim();
+        let msg = match command {
+            "/join" if !arg.is_empty() => {
+                current_room = Some(arg.to_string());
+                Message::JoinRoom(arg.to_string())
+            }
+            "/leave" => {
+                let room = match (arg, &current_room) {
+                    ("", Some(room)) => room.clone(),
+                    ("", None) => continue,
+                    (room, _) => room.to_string(),
+                };
+                if current_room.as_ref() == Some(&room) {
+                    current_room = None;
+                }
+                Message::LeaveRoom(room)
+            }
+            "/rooms" => Message::ListRooms,
+            "/who" => Message::ListUsers,
+            "/msg" => match arg.split_once(' ') {
+                Some((to, content)) => Message::PrivateMessage {
+                    to: to.to_string(),
+                    content: content.trim_start().to_string(),
+                },
+                None => {
+                    eprintln!("Usage: /msg <user> <message>");
+                    continue;
+                }
+            },
+            _ => match &current_room {
+                Some(room) => Message::ClientMessage {
+                    room: room.clone(),
+                    content: line,
+                },
+                None => {
+                    eprintln!("You are not in a room, /join one first");
+                    continue;
+                }
+            },
+        };
+        tcp_write.write_all(&codec.encode(&msg)?).await?;
     }
+    // The server closes the connection once we are done. Over TLS, it only learns that from
+    // the close_notify a shutdown sends, not from the writer being dropped.
+    tcp_write.shutdown().await?;
     Ok(())
 }
 
-async fn handle_incoming_chats(tcp_read: OwnedReadHalf) -> Result<()> {
-    let mut tcp_read = BufReader::new(tcp_read).lines();
-    while let Ok(Some(message)) = tcp_read.next_line().await {
-        match serde_json::from_str(&message)? {
-            Message::Chat { content, user } => {
-                println!("<{user}>: {content}")
-            }
-            Message::User(username) => {
-                println!("<{username}> joined the chat")
-            }
-            _ => {} // Let's just ignore these
+async fn handle_incoming_chats(tcp_read: Reader, codec: Codec, format: Format) -> Result<()> {
+    let mut tcp_read = BufReader::new(tcp_read);
+    while let Some(message) = codec.read(&mut tcp_read).await? {
+        match format {
+            Format::Text => print_message(message),
+            Format::Json => println!("{}", serde_json::to_string(&message)?),
         }
     }
 
     Ok(())
 }
+
+fn print_message(message: Message) {
+    match message {
+        Message::Chat {
+            room,
+            content,
+            user,
+            sent_at,
+        } => {
+            // Times are shown in UTC, as the standard library knows no time zones.
+            let (hours, minutes) = (sent_at / 3600 % 24, sent_at / 60 % 60);
+            println!("[{hours:02}:{minutes:02}] #{room} <{user}>: {content}")
+        }
+        Message::User(username) => {
+            println!("<{username}> joined the chat")
+        }
+        Message::Joined { room, user } => {
+            println!("<{user}> joined #{room}")
+        }
+        Message::Left { room, user } => {
+            println!("<{user}> left #{room}")
+        }
+        Message::Rooms(rooms) if rooms.is_empty() => println!("There are no rooms"),
+        Message::Rooms(rooms) => {
+            println!("Rooms: #{}", rooms.join(", #"))
+        }
+        Message::Private { from, content } => {
+            println!("*{from}* {content}")
+        }
+        Message::Error { message, fatal, .. } => {
+            println!("Error: {message}");
+            if fatal {
+                println!("The server is closing the connection");
+            }
+        }
+        Message::System(notice) => {
+            println!("*** {notice}")
+        }
+        Message::Missed { room, count } => {
+            let channel = room.map_or("announcements".to_string(), |room| format!("#{room}"));
+            println!("*** You fell behind and missed {count} message(s) in {channel}")
+        }
+        Message::Users(users) => {
+            println!("Online: {}", users.join(", "))
+        }
+        Message::Quit(username) => {
+            println!("<{username}> left the chat")
+        }
+        // Only clients send these.
+        Message::Codec(_)
+        | Message::ClientMessage { .. }
+        | Message::JoinRoom(_)
+        | Message::LeaveRoom(_)
+        | Message::ListRooms
+        | Message::ListUsers
+        | Message::PrivateMessage { .. } => {}
+    }
+}
@@ -1,99 +1,74 @@
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc};
 
 use anyhow::{bail, Result};
-use chat::{serialize_message, Message};
-use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpListener,
-    },
-    sync::broadcast,
-    task,
+use chat::{
+    history::History,
+    server::{Config, Server},
+    tls,
 };
-use tokio_stream::{wrappers::BroadcastStream, StreamExt};
+use clap::Parser;
+use tokio::{io, net::TcpListener, task};
+
+#[derive(Parser)]
+#[command(version, about = "A chat server")]
+struct Args {
+    /// The address to listen on; port 0 picks a free port
+    #[arg(long, env = "CHAT_LISTEN", default_value = "127.0.0.1:8000")]
+    listen: String,
+    /// The number of messages kept per room, for users joining it
+    #[arg(long, env = "CHAT_HISTORY_LEN", default_value_t = 50)]
+    history_len: usize,
+    /// Save the history to this file, so it survives restarts
+    #[arg(long, env = "CHAT_HISTORY_FILE")]
+    history_file: Option<PathBuf>,
+    /// Serve TLS, with the certificate chain in this PEM file
+    #[arg(long, env = "CHAT_TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+    /// The private key of the certificate, in a PEM file
+    #[arg(long, env = "CHAT_TLS_KEY", conflicts_with = "tls_self_signed")]
+    tls_key: Option<PathBuf>,
+    /// Serve TLS with a certificate for localhost generated on startup, for local development.
+    /// It is written to --tls-cert, for clients to trust.
+    #[arg(long, env = "CHAT_TLS_SELF_SIGNED", requires = "tls_cert")]
+    tls_self_signed: bool,
+    #[command(flatten)]
+    config: Config,
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let tcp_listener = TcpListener::bind("127.0.0.1:8000").await?;
-    let (tx, _) = broadcast::channel(1024);
-    let tx = Arc::new(tx);
+    let args = Args::parse();
+    let history = match args.history_file {
+        Some(path) => History::open(path, args.history_len).await?,
+        None => History::new(args.history_len),
+    };
+    let acceptor = match (&args.tls_cert, &args.tls_key, args.tls_self_signed) {
+        (None, None, false) => None,
+        (Some(cert), Some(key), false) => Some(tls::acceptor(cert, key)?),
+        (Some(cert), None, true) => Some(tls::self_signed_acceptor(cert)?),
+        _ => bail!("TLS needs --tls-cert along with either --tls-key or --tls-self-signed"),
+    };
+    let tcp_listener = TcpListener::bind(&args.listen).await?;
+    println!("Listening on {}", tcp_listener.local_addr()?);
+    let server = Arc::new(Server::new(history, args.config));
     loop {
         let (stream, _) = tcp_listener.accept().await?;
-        let (tcp_read, tcp_write) = stream.into_split();
         println!("Connection established");
-
-        task::spawn({
-            let tx = tx.clone();
-            async {
-                handle_incoming(tcp_read, tx).await.ok();
-            }
-        });
-
-        task::spawn({
-            let rx = tx.subscribe();
-            async {
-                handle_outgoing(tcp_write, rx).await.ok();
+        let Some(acceptor) = &acceptor else {
+            let (tcp_read, tcp_write) = stream.into_split();
+            server.connect(tcp_read, tcp_write);
+            continue;
+        };
+        // The handshake gets a task of its own, so a slow client does not hold up the others.
+        let (acceptor, server) = (acceptor.clone(), server.clone());
+        task::spawn(async move {
+            match acceptor.accept(stream).await {
+                Ok(stream) => {
+                    let (tls_read, tls_write) = io::split(stream);
+                    server.connect(tls_read, tls_write);
+                }
+                Err(e) => eprintln!("TLS handshake failed: {e}"),
             }
         });
     }
 }
-
-async fn handle_incoming(
-    tcp_read: OwnedReadHalf,
-    tx: impl AsRef<broadcast::Sender<Message>>,
-) -> Result<()> {
-    let mut tcp_read = BufReader::new(tcp_read).lines();
-    let Some(initial_message) = tcp_read.next_line().await? else {
-        return Ok(());
-    };
-    // todo!(
-    //     "Deserialize initial_message into a Message::User.
-    //         If the initial line is not a Message::User, stop this task."
-    // );
-    let init_msg: Message = serde_json::from_str(&initial_message)?;
-    let Message::User(user) = init_msg.clone() else {
-        bail!(
-            "Expected the initial message to be Message::User, but received: {:?}",
-            init_msg,
-        );
-    };
-    let tx = tx.as_ref();
-    tx.send(init_msg)?;
-
-    // todo!("For each further incoming line, deserialize the line into a Message");
-    // todo!("If the message is a Message::User, broadcast the message as-is using tx");
-    // todo!(
-    //     "If the message is a Message::ClientMessage,
-    //     convert it into a Message::Chat and broadcast it using tx"
-    // );
-    // todo!("If the message is a Message::Chat, ignore it");
-    while let Some(line) = tcp_read.next_line().await? {
-        let msg: Message = serde_json::from_str(&line)?;
-        match msg {
-            Message::User(_) => tx.send(msg)?,
-            Message::ClientMessage(content) => tx.send(Message::Chat {
-                user: user.clone(),
-                content,
-            })?,
-            Message::Chat { .. } => continue,
-        };
-    }
-
-    Ok(())
-}
-
-async fn handle_outgoing(
-    mut tcp_write: OwnedWriteHalf,
-    rx: broadcast::Receiver<Message>,
-) -> Result<()> {
-    let mut rx = BroadcastStream::from(rx);
-    while let Some(Ok(msg)) = rx.next().await {
-        // todo!(
-        //     "Serialize message as JSON and send it to the client,
-        //     along with a newline"
-        // );
-        tcp_write.write_all(&serialize_message(msg)?).await?;
-    }
-    Ok(())
-}
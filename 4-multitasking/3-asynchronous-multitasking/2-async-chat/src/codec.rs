@@ -0,0 +1,173 @@
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+use crate::Message;
+
+/// The longest message either side accepts, in bytes
+pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+/// How messages are written on the wire.
+///
+/// A connection starts out with `Json`. A client picks another codec by sending
+/// `Message::Codec` as its very first message, after which both sides switch to it.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Codec {
+    /// One JSON object per line
+    #[default]
+    Json,
+    /// A 4 byte big-endian length followed by the message in bincode
+    Binary,
+}
+
+impl Codec {
+    /// Encodes a message into a frame, ready to be written
+    pub fn encode(self, msg: &Message) -> Result<Vec<u8>> {
+        match self {
+            Codec::Json => {
+                let mut json = serde_json::to_vec(msg)?;
+                json.push(b'\n');
+                Ok(json)
+            }
+            Codec::Binary => {
+                let body = bincode::serialize(msg)?;
+                let len = u32::try_from(body.len()).context("Message too long")?;
+                let mut frame = Vec::with_capacity(4 + body.len());
+                frame.extend_from_slice(&len.to_be_bytes());
+                frame.extend_from_slice(&body);
+                Ok(frame)
+            }
+        }
+    }
+
+    /// Reads the next message, or `None` if the connection was closed between two messages
+    pub async fn read<R: AsyncBufRead + Unpin>(self, reader: &mut R) -> Result<Option<Message>> {
+        match self {
+            Codec::Json => {
+                let mut line = String::new();
+                // One more byte than allowed, to tell a long line from one that is just right.
+                let limit = MAX_FRAME_LEN as u64 + 1;
+                if (&mut *reader).take(limit).read_line(&mut line).await? == 0 {
+                    return Ok(None);
+                }
+                if line.len() > MAX_FRAME_LEN {
+                    bail!("Message longer than {MAX_FRAME_LEN} bytes");
+                }
+                let line = line.trim_end_matches(['\n', '\r']);
+                let msg = serde_json::from_str(line)
+                    .with_context(|| format!("Malformed message: {line}"))?;
+                Ok(Some(msg))
+            }
+            Codec::Binary => {
+                if reader.fill_buf().await?.is_empty() {
+                    return Ok(None);
+                }
+                let mut len = [0; 4];
+                reader.read_exact(&mut len).await?;
+                let len = u32::from_be_bytes(len) as usize;
+                if len > MAX_FRAME_LEN {
+                    bail!("Message longer than {MAX_FRAME_LEN} bytes");
+                }
+                let mut body = vec![0; len];
+                reader.read_exact(&mut body).await?;
+                let msg = bincode::deserialize(&body).context("Malformed message")?;
+                Ok(Some(msg))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CODECS: [Codec; 2] = [Codec::Json, Codec::Binary];
+
+    fn chat(content: &str) -> Message {
+        Message::Chat {
+            room: "lobby".to_string(),
+            user: "alice".to_string(),
+            content: content.to_string(),
+            sent_at: 1_700_000_000,
+        }
+    }
+
+    /// A message whose frame counts exactly `len` bytes against `MAX_FRAME_LEN`
+    fn sized(codec: Codec, len: usize) -> Message {
+        // The length prefix of a binary frame does not count.
+        let overhead = match codec {
+            Codec::Json => 0,
+            Codec::Binary => 4,
+        };
+        let empty = codec.encode(&chat("")).unwrap().len() - overhead;
+        chat(&"a".repeat(len - empty))
+    }
+
+    async fn decode(codec: Codec, mut bytes: &[u8]) -> Result<Option<Message>> {
+        codec.read(&mut bytes).await
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        for codec in CODECS {
+            let msgs = [
+                chat("hello"),
+                chat("two\nlines"),
+                Message::Missed {
+                    room: None,
+                    count: 3,
+                },
+                Message::Codec(Codec::Binary),
+            ];
+            let mut bytes = Vec::new();
+            for msg in &msgs {
+                bytes.extend(codec.encode(msg).unwrap());
+            }
+            let mut reader = &bytes[..];
+            for msg in &msgs {
+                assert_eq!(codec.read(&mut reader).await.unwrap().as_ref(), Some(msg));
+            }
+            assert!(codec.read(&mut reader).await.unwrap().is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_newline_is_escaped() {
+        let frame = Codec::Json.encode(&chat("two\nlines")).unwrap();
+        assert_eq!(frame.iter().filter(|&&b| b == b'\n').count(), 1);
+        assert_eq!(frame.last(), Some(&b'\n'));
+    }
+
+    #[tokio::test]
+    async fn test_frame_len() {
+        for codec in CODECS {
+            let longest = sized(codec, MAX_FRAME_LEN);
+            let frame = codec.encode(&longest).unwrap();
+            assert_eq!(decode(codec, &frame).await.unwrap(), Some(longest));
+
+            let frame = codec.encode(&sized(codec, MAX_FRAME_LEN + 1)).unwrap();
+            let error = decode(codec, &frame).await.unwrap_err();
+            assert!(
+                error.to_string().contains("longer than"),
+                "{codec:?}: {error}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_truncated_binary_frame() {
+        let frame = Codec::Binary.encode(&chat("hello")).unwrap();
+        // Cut in the length, then in the body.
+        for cut in [2, frame.len() - 1] {
+            assert!(decode(Codec::Binary, &frame[..cut]).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_eof_between_frames() {
+        for codec in CODECS {
+            assert!(decode(codec, b"").await.unwrap().is_none());
+        }
+    }
+}
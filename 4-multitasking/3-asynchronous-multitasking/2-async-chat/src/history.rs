@@ -0,0 +1,285 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use tokio::{
+    fs,
+    io::AsyncWriteExt,
+    sync::mpsc::{self, error::TrySendError},
+    task::{self, JoinHandle},
+};
+
+use crate::{codec::Codec, Message};
+
+/// How many writes to the history file may be waiting at once
+const PENDING_WRITES: usize = 1024;
+
+/// The last few `Message::Chat`s of every room, to replay to users joining it
+pub struct History {
+    /// The most messages kept per room
+    len: usize,
+    rooms: HashMap<String, VecDeque<Message>>,
+    file: Option<HistoryFile>,
+}
+
+/// The file a history is saved to, written by a task of its own
+struct HistoryFile {
+    writes: mpsc::Sender<Write>,
+    task: JoinHandle<()>,
+    /// How many messages the file holds, once the pending writes are done,
+    /// including those no longer kept
+    lines: usize,
+    /// Whether a write was dropped, so the file has to be rewritten
+    stale: bool,
+}
+
+enum Write {
+    /// A message to add at the end of the file
+    Append(Vec<u8>),
+    /// The whole content of the file
+    Replace(Vec<u8>),
+}
+
+impl History {
+    /// A history that is lost when the server stops
+    pub fn new(len: usize) -> Self {
+        History {
+            len,
+            rooms: HashMap::new(),
+            file: None,
+        }
+    }
+
+    /// Loads the history saved in a file, which is created if needed,
+    /// and saves every message recorded from now on to it
+    pub async fn open(path: impl AsRef<Path>, len: usize) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut history = History::new(len);
+        match fs::read_to_string(&path).await {
+            Ok(saved) => {
+                // A line cut short by a crash is skipped.
+                for msg in saved
+                    .lines()
+                    .filter_map(|line| serde_json::from_str(line).ok())
+                {
+                    history.remember(msg);
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        // Only what is kept is written back, so the file does not grow forever.
+        let file = replace(&path, &history.saved()).await?;
+        let (writes, rx) = mpsc::channel(PENDING_WRITES);
+        history.file = Some(HistoryFile {
+            writes,
+            task: task::spawn(save(path, file, rx)),
+            lines: history.kept(),
+            stale: false,
+        });
+        Ok(history)
+    }
+
+    /// Records a message, if it is a `Message::Chat`
+    pub fn record(&mut self, msg: &Message) {
+        if self.len == 0 || !matches!(msg, Message::Chat { .. }) {
+            return;
+        }
+        self.remember(msg.clone());
+        let Some(file) = &self.file else {
+            return;
+        };
+
+        // Once the file holds twice what is kept, it is replaced by what is kept.
+        let kept = self.kept();
+        let write = if file.stale || file.lines >= 2 * kept.max(self.len) {
+            Write::Replace(self.saved())
+        } else {
+            match Codec::Json.encode(msg) {
+                Ok(line) => Write::Append(line),
+                Err(_) => return,
+            }
+        };
+        let lines = match write {
+            Write::Append(_) => file.lines + 1,
+            Write::Replace(_) => kept,
+        };
+
+        let file = self.file.as_mut().unwrap();
+        match file.writes.try_send(write) {
+            Ok(()) => {
+                file.lines = lines;
+                file.stale = false;
+            }
+            // The disk cannot keep up, so the file catches up with a later message.
+            Err(TrySendError::Full(_)) => file.stale = true,
+            // The file could not be written, which was reported already.
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+
+    /// The messages kept for a room, oldest first
+    pub fn replay(&self, room: &str) -> Vec<Message> {
+        self.rooms
+            .get(room)
+            .map(|msgs| msgs.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Waits for everything recorded to be saved
+    pub async fn close(self) {
+        if let Some(file) = self.file {
+            drop(file.writes);
+            file.task.await.ok();
+        }
+    }
+
+    fn remember(&mut self, msg: Message) {
+        let Message::Chat { room, .. } = &msg else {
+            return;
+        };
+        let msgs = self.rooms.entry(room.clone()).or_default();
+        msgs.push_back(msg);
+        if msgs.len() > self.len {
+            msgs.pop_front();
+        }
+    }
+
+    /// How many messages are kept, across all rooms
+    fn kept(&self) -> usize {
+        self.rooms.values().map(VecDeque::len).sum()
+    }
+
+    /// The messages kept, as saved in the file
+    fn saved(&self) -> Vec<u8> {
+        self.rooms
+            .values()
+            .flatten()
+            .filter_map(|msg| Codec::Json.encode(msg).ok())
+            .flatten()
+            .collect()
+    }
+}
+
+/// Writes to the history file until the history is closed, or writing fails
+async fn save(path: PathBuf, mut file: fs::File, mut writes: mpsc::Receiver<Write>) {
+    while let Some(write) = writes.recv().await {
+        let written = match write {
+            Write::Append(line) => append(&mut file, &line).await,
+            Write::Replace(content) => replace(&path, &content).await.map(|new| file = new),
+        };
+        if let Err(e) = written {
+            eprintln!("Failed to save the chat history: {e}");
+            return;
+        }
+    }
+}
+
+async fn append(file: &mut fs::File, line: &[u8]) -> io::Result<()> {
+    file.write_all(line).await?;
+    // Hands the line over to the system now, rather than when the next one is written.
+    file.flush().await
+}
+
+/// Replaces the file through a temporary one, so a crash leaves either the old content or the
+/// new, and opens it for appending
+async fn replace(path: &Path, content: &[u8]) -> io::Result<fs::File> {
+    let tmp = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp).await?;
+    file.write_all(content).await?;
+    file.sync_all().await?;
+    fs::rename(&tmp, path).await?;
+    fs::OpenOptions::new().append(true).open(path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(room: &str, content: usize) -> Message {
+        Message::Chat {
+            room: room.to_string(),
+            user: "alice".to_string(),
+            content: content.to_string(),
+            sent_at: 0,
+        }
+    }
+
+    fn contents(msgs: &[Message]) -> Vec<String> {
+        msgs.iter()
+            .map(|msg| match msg {
+                Message::Chat { content, .. } => content.clone(),
+                other => panic!("not a chat: {other:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_len() {
+        let mut history = History::new(3);
+        for i in 0..5 {
+            history.record(&chat("a", i));
+        }
+        history.record(&chat("b", 0));
+        history.record(&Message::System("not a chat".to_string()));
+
+        assert_eq!(contents(&history.replay("a")), ["2", "3", "4"]);
+        assert_eq!(contents(&history.replay("b")), ["0"]);
+        assert!(history.replay("c").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history");
+        let mut history = History::open(&path, 3).await.unwrap();
+        for i in 0..5 {
+            history.record(&chat("a", i));
+            history.record(&chat("b", i));
+        }
+        history.close().await;
+
+        let history = History::open(&path, 3).await.unwrap();
+        assert_eq!(contents(&history.replay("a")), ["2", "3", "4"]);
+        assert_eq!(contents(&history.replay("b")), ["2", "3", "4"]);
+        // Only what is kept is left in the file.
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(saved.lines().count(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_last_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history");
+        let mut saved = Vec::new();
+        for i in 0..2 {
+            saved.extend(Codec::Json.encode(&chat("a", i)).unwrap());
+        }
+        let last = Codec::Json.encode(&chat("a", 2)).unwrap();
+        saved.extend(&last[..last.len() / 2]);
+        std::fs::write(&path, saved).unwrap();
+
+        let history = History::open(&path, 10).await.unwrap();
+        assert_eq!(contents(&history.replay("a")), ["0", "1"]);
+    }
+
+    #[tokio::test]
+    async fn test_file_is_bounded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history");
+        let mut history = History::open(&path, 2).await.unwrap();
+        for i in 0..100 {
+            history.record(&chat("a", i));
+        }
+        history.close().await;
+
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert!(saved.lines().count() <= 4, "{saved}");
+        let history = History::open(&path, 2).await.unwrap();
+        assert_eq!(contents(&history.replay("a")), ["98", "99"]);
+    }
+}
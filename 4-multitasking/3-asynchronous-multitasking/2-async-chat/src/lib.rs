@@ -1,20 +1,106 @@
-use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+pub mod codec;
+pub mod history;
+pub mod server;
+pub mod tls;
+
+use codec::Codec;
+
+/// The room every user is in after entering the chat
+pub const DEFAULT_ROOM: &str = "general";
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum Message {
-    /// A user enters the chat and provides their username
+    /// Optionally sent by a client before anything else, always as JSON,
+    /// to have both sides switch to another codec
+    Codec(Codec),
+    /// A user enters the chat and provides their username,
+    /// which the server then announces to everyone
     User(String),
-    /// A message sent from a client,
+    /// A message sent from a client to a room it has joined,
     /// that needs to be matched with their username
-    ClientMessage(String),
-    /// A message sent from the server to the clients,
+    ClientMessage { room: String, content: String },
+    /// A message sent from the server to the members of a room,
     /// containing the username of the sender and the message content
-    Chat { user: String, content: String },
+    Chat {
+        room: String,
+        user: String,
+        content: String,
+        /// When the server received the message, in seconds since the Unix epoch
+        sent_at: u64,
+    },
+    /// A client asks to join a room, creating it if nobody is in it yet
+    JoinRoom(String),
+    /// A client asks to leave a room
+    LeaveRoom(String),
+    /// A client asks which rooms exist
+    ListRooms,
+    /// The server's answer to `ListRooms`
+    Rooms(Vec<String>),
+    /// Sent by the server to the members of a room when a user joins it
+    Joined { room: String, user: String },
+    /// Sent by the server to the members of a room, and to the user, when a user leaves it
+    Left { room: String, user: String },
+    /// A private message sent from a client to another user
+    PrivateMessage { to: String, content: String },
+    /// A private message delivered by the server to its recipient only
+    Private { from: String, content: String },
+    /// Sent by the server when it cannot do what a client asked
+    Error {
+        kind: ErrorKind,
+        message: String,
+        /// Whether the server closes the connection right after sending this
+        fatal: bool,
+    },
+    /// A notice from the server itself, not from any user
+    System(String),
+    /// Sent by the server when a client fell so far behind that messages were skipped,
+    /// in a room, or in the announcements to everyone if `room` is `None`
+    Missed { room: Option<String>, count: u64 },
+    /// A client asks who is online
+    ListUsers,
+    /// The server's answer to `ListUsers`
+    Users(Vec<String>),
+    /// Sent by the server to everyone when a user disconnects, leaving all of their rooms
+    Quit(String),
 }
 
-pub fn serialize_message(msg: Message) -> Result<Vec<u8>> {
-    let mut json = serde_json::to_vec(&msg)?;
-    json.push(b'\n');
-    Ok(json)
+/// What went wrong, in a `Message::Error`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The client broke the protocol, e.g. by sending something that is not a `Message`
+    Protocol,
+    /// The username is invalid or taken by someone else
+    Username,
+    /// The recipient of a private message is not online
+    UserOffline,
+    /// The recipient of a private message is too far behind to take any more for now
+    UserBusy,
+    /// The client tried to talk in a room it has not joined
+    NotInRoom,
+    /// The client fell too far behind in receiving messages
+    Lagged,
+    /// As many users as the server allows are online already
+    Full,
+}
+
+impl Message {
+    /// An error the connection survives
+    pub fn error(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Message::Error {
+            kind,
+            message: message.into(),
+            fatal: false,
+        }
+    }
+
+    /// An error after which the server closes the connection
+    pub fn fatal_error(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Message::Error {
+            kind,
+            message: message.into(),
+            fatal: true,
+        }
+    }
 }
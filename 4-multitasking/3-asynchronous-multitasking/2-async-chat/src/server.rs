@@ -0,0 +1,609 @@
+//! The chat server, apart from accepting connections
+
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    sync::{broadcast, mpsc, mpsc::error::TrySendError, oneshot, Notify, Semaphore},
+    task, time,
+};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    StreamExt, StreamMap,
+};
+
+use crate::{codec::Codec, history::History, ErrorKind, Message, DEFAULT_ROOM};
+
+/// What to do when a client falls so far behind that its messages no longer fit in a channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum LagPolicy {
+    /// Skip the oldest messages and tell the client how many it missed
+    #[default]
+    Drop,
+    /// Close the connection, after telling the client why
+    Disconnect,
+    /// Make users wait before talking in a room until its slowest member has caught up.
+    /// One member that stops reading holds up the whole room. Messages sent by the server
+    /// itself do not wait, so a client may still be told it missed some.
+    Backpressure,
+}
+
+const DEFAULT_CAPACITY: usize = 1024;
+
+/// How long a client refused for lack of room has to say which codec it talks
+const REFUSAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How the server behaves, apart from where it listens
+#[derive(Debug, Clone, Copy, clap::Args)]
+pub struct Config {
+    /// How many messages a channel holds for members that are slow to receive them
+    #[arg(
+        long,
+        env = "CHAT_CAPACITY",
+        default_value_t = DEFAULT_CAPACITY,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+    )]
+    pub capacity: usize,
+    /// What to do when a client falls further behind than the capacity
+    #[arg(long, env = "CHAT_LAG_POLICY", value_enum, default_value_t = LagPolicy::Drop)]
+    pub lag_policy: LagPolicy,
+    /// The most clients connected at once, whether they have entered the chat or not;
+    /// there is no limit if unset
+    #[arg(long, env = "CHAT_MAX_CLIENTS")]
+    pub max_clients: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            capacity: DEFAULT_CAPACITY,
+            lag_policy: LagPolicy::default(),
+            max_clients: None,
+        }
+    }
+}
+
+/// Where broadcast messages come from
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Channel {
+    /// Messages for every connected user
+    Everyone,
+    Room(String),
+}
+
+/// What the incoming half of a connection asks its outgoing half to do
+#[derive(Debug)]
+enum Event {
+    /// Start forwarding the messages of a channel to the client
+    Subscribe(Channel, broadcast::Receiver<Message>),
+    /// Stop forwarding the messages of a channel to the client
+    Unsubscribe(Channel),
+    /// Send a message to this client only
+    Send(Message),
+    /// Encode everything from now on with another codec
+    Codec(Codec),
+}
+
+struct Room {
+    tx: broadcast::Sender<Message>,
+    /// The number of connections in the room; it is removed once this drops to zero
+    members: usize,
+}
+
+struct RoomMap {
+    rooms: HashMap<String, Room>,
+    /// Outlives the rooms, so a room that empties keeps its history
+    history: History,
+}
+
+/// Every room that has members, by name, and what was said in them
+struct Rooms {
+    map: Mutex<RoomMap>,
+    capacity: usize,
+    /// Signalled when a member receives a message or stops listening, which may make space
+    /// in a room. Only used with `LagPolicy::Backpressure`.
+    caught_up: Notify,
+}
+
+impl Rooms {
+    fn new(history: History, capacity: usize) -> Self {
+        Rooms {
+            map: Mutex::new(RoomMap {
+                rooms: HashMap::new(),
+                history,
+            }),
+            capacity,
+            caught_up: Notify::new(),
+        }
+    }
+
+    /// Adds a member to a room, creating it if needed, and returns a receiver for its messages
+    /// along with its history. Both are taken under the same lock, so no message is missed or
+    /// received twice in between.
+    fn join(&self, name: &str) -> (broadcast::Receiver<Message>, Vec<Message>) {
+        let mut map = self.map.lock().unwrap();
+        let room = map.rooms.entry(name.to_string()).or_insert_with(|| Room {
+            tx: broadcast::channel(self.capacity).0,
+            members: 0,
+        });
+        room.members += 1;
+        let rx = room.tx.subscribe();
+        (rx, map.history.replay(name))
+    }
+
+    fn leave(&self, name: &str) {
+        let rooms = &mut self.map.lock().unwrap().rooms;
+        if let Some(room) = rooms.get_mut(name) {
+            room.members -= 1;
+            if room.members == 0 {
+                rooms.remove(name);
+            }
+        }
+    }
+
+    /// Broadcasts a message to every member of a room, keeping it in the history if it is a chat
+    fn send(&self, name: &str, msg: Message) {
+        let mut map = self.map.lock().unwrap();
+        if let Some(room) = map.rooms.get(name) {
+            // This only fails if no member is listening anymore.
+            room.tx.send(msg.clone()).ok();
+            map.history.record(&msg);
+        }
+    }
+
+    /// Waits until a room has space for another message, i.e. its slowest member has caught up
+    async fn ready(&self, name: &str) {
+        loop {
+            // Waiting starts before checking, so a member catching up in between is not missed.
+            let caught_up = self.caught_up.notified();
+            tokio::pin!(caught_up);
+            caught_up.as_mut().enable();
+            let full = self
+                .map
+                .lock()
+                .unwrap()
+                .rooms
+                .get(name)
+                .is_some_and(|room| room.tx.len() >= self.capacity);
+            if !full {
+                return;
+            }
+            caught_up.await;
+        }
+    }
+
+    fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.map.lock().unwrap().rooms.keys().cloned().collect();
+        names.sort();
+        names
+    }
+}
+
+/// Every connected user, by name, with the channel to their connection's outgoing half
+#[derive(Default)]
+struct Users {
+    map: Mutex<HashMap<String, mpsc::Sender<Event>>>,
+}
+
+impl Users {
+    /// Adds a user, or returns the error to refuse them with if their name is taken
+    fn register(&self, user: &str, events: mpsc::Sender<Event>) -> Result<(), Message> {
+        match self.map.lock().unwrap().entry(user.to_string()) {
+            Entry::Occupied(_) => {
+                let error = format!("The username {user} is already taken");
+                Err(Message::fatal_error(ErrorKind::Username, error))
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(events);
+                Ok(())
+            }
+        }
+    }
+
+    fn unregister(&self, user: &str) {
+        self.map.lock().unwrap().remove(user);
+    }
+
+    fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.map.lock().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Sends a message to a single user, or returns the error to tell the sender with if they
+    /// are not connected. This never waits, so a user who stops reading cannot hold up others:
+    /// messages that do not fit in their queue are refused instead.
+    fn send(&self, user: &str, msg: Message) -> Result<(), Message> {
+        let offline = || Message::error(ErrorKind::UserOffline, format!("{user} is not online"));
+        let Some(tx) = self.map.lock().unwrap().get(user).cloned() else {
+            return Err(offline());
+        };
+        match tx.try_send(Event::Send(msg)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                let error =
+                    format!("{user} is not keeping up with their messages, try again later");
+                Err(Message::error(ErrorKind::UserBusy, error))
+            }
+            Err(TrySendError::Closed(_)) => Err(offline()),
+        }
+    }
+}
+
+/// Everything the connections share
+pub struct Server {
+    rooms: Rooms,
+    users: Users,
+    /// Announces users entering and leaving the chat
+    everyone: broadcast::Sender<Message>,
+    lag_policy: LagPolicy,
+    /// A permit for every connection, if their number is limited
+    connections: Option<Arc<Semaphore>>,
+}
+
+impl Server {
+    pub fn new(history: History, config: Config) -> Self {
+        // Channels cannot be empty.
+        let capacity = config.capacity.max(1);
+        Server {
+            rooms: Rooms::new(history, capacity),
+            users: Users::default(),
+            everyone: broadcast::channel(capacity).0,
+            lag_policy: config.lag_policy,
+            connections: config.max_clients.map(|max| Arc::new(Semaphore::new(max))),
+        }
+    }
+
+    /// Serves a client until it disconnects, from tasks of its own
+    pub fn connect<R, W>(self: &Arc<Self>, read: R, write: W)
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let permit = match &self.connections {
+            Some(connections) => match connections.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    task::spawn(refuse(read, write));
+                    return;
+                }
+            },
+            None => None,
+        };
+        let (events_tx, events_rx) = mpsc::channel(32);
+        // Dropped once the outgoing half stops, e.g. to disconnect a lagging client, which
+        // stops the incoming half too.
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let incoming = task::spawn({
+            let server = self.clone();
+            async {
+                tokio::select! {
+                    _ = handle_incoming(read, server, events_tx) => {}
+                    _ = stop_rx => {}
+                }
+            }
+        });
+
+        let server = self.clone();
+        task::spawn(async move {
+            let mut write = write;
+            handle_outgoing(&mut write, events_rx, &server).await.ok();
+            drop(stop_tx);
+            // The user has left by the time the client sees the connection close.
+            incoming.await.ok();
+            write.shutdown().await.ok();
+            // The connection's receivers are gone, so they no longer hold up any room.
+            server.caught_up();
+            drop(permit);
+        });
+    }
+
+    /// Wakes up users waiting for a room to have space, under `LagPolicy::Backpressure`
+    fn caught_up(&self) {
+        if self.lag_policy == LagPolicy::Backpressure {
+            self.rooms.caught_up.notify_waiters();
+        }
+    }
+}
+
+/// A connected user and the rooms they have joined. They are left, and the user is announced
+/// to have quit, when the connection ends.
+struct Member {
+    user: String,
+    joined: HashSet<String>,
+    server: Arc<Server>,
+    events: mpsc::Sender<Event>,
+}
+
+impl Member {
+    /// Registers a user under a name nobody else has, if there is room for them,
+    /// and announces them to everyone
+    async fn enter(
+        user: String,
+        server: Arc<Server>,
+        events: mpsc::Sender<Event>,
+    ) -> Result<Option<Self>> {
+        let registered = if user.is_empty() {
+            let error = "Usernames must not be empty";
+            Err(Message::fatal_error(ErrorKind::Username, error))
+        } else {
+            server.users.register(&user, events.clone())
+        };
+        if let Err(error) = registered {
+            events.send(Event::Send(error)).await?;
+            return Ok(None);
+        }
+        let mut member = Member {
+            user: user.clone(),
+            joined: HashSet::new(),
+            server: server.clone(),
+            events: events.clone(),
+        };
+        let rx = server.everyone.subscribe();
+        events.send(Event::Subscribe(Channel::Everyone, rx)).await?;
+        server.everyone.send(Message::User(user.clone())).ok();
+        member.subscribe(DEFAULT_ROOM).await?;
+        let online = server.users.names().len();
+        let welcome =
+            format!("Welcome, {user}! You are in #{DEFAULT_ROOM}, {online} user(s) online");
+        events.send(Event::Send(Message::System(welcome))).await?;
+        Ok(Some(member))
+    }
+
+    /// Adds the user to a room without telling anyone, and replays what was said in it last.
+    /// Returns `false` if they are in it already.
+    async fn subscribe(&mut self, room: &str) -> Result<bool> {
+        if self.joined.contains(room) {
+            return Ok(false);
+        }
+        let (rx, history) = self.server.rooms.join(room);
+        self.joined.insert(room.to_string());
+        // The history goes out before anything said after it.
+        for msg in history {
+            self.events.send(Event::Send(msg)).await?;
+        }
+        let channel = Channel::Room(room.to_string());
+        self.events.send(Event::Subscribe(channel, rx)).await?;
+        Ok(true)
+    }
+
+    async fn join(&mut self, room: String) -> Result<()> {
+        if !self.subscribe(&room).await? {
+            return Ok(());
+        }
+        let joined = Message::Joined {
+            room: room.clone(),
+            user: self.user.clone(),
+        };
+        self.server.rooms.send(&room, joined);
+        Ok(())
+    }
+
+    async fn leave(&mut self, room: String) -> Result<()> {
+        if !self.joined.remove(&room) {
+            return Ok(());
+        }
+        // Unsubscribe first, so the user is told they left exactly once.
+        let channel = Channel::Room(room.clone());
+        self.events.send(Event::Unsubscribe(channel)).await?;
+        self.server.rooms.leave(&room);
+        let left = Message::Left {
+            room: room.clone(),
+            user: self.user.clone(),
+        };
+        self.server.rooms.send(&room, left.clone());
+        self.events.send(Event::Send(left)).await?;
+        Ok(())
+    }
+}
+
+impl Drop for Member {
+    fn drop(&mut self) {
+        self.server.users.unregister(&self.user);
+        for room in self.joined.drain() {
+            self.server.rooms.leave(&room);
+        }
+        let quit = Message::Quit(self.user.clone());
+        self.server.everyone.send(quit).ok();
+    }
+}
+
+/// Tells a client that the server is full, in the codec it chose, and closes the connection
+async fn refuse(read: impl AsyncRead + Unpin, mut write: impl AsyncWrite + Unpin) {
+    let mut read = BufReader::new(read);
+    // Clients that do not choose a codec with their first message stick to JSON.
+    let first = time::timeout(REFUSAL_TIMEOUT, Codec::Json.read(&mut read)).await;
+    let codec = match first {
+        Ok(Ok(Some(Message::Codec(codec)))) => codec,
+        _ => Codec::Json,
+    };
+    let error = Message::fatal_error(ErrorKind::Full, "The server is full, try again later");
+    if let Ok(frame) = codec.encode(&error) {
+        write.write_all(&frame).await.ok();
+    }
+    write.shutdown().await.ok();
+}
+
+async fn handle_incoming(
+    tcp_read: impl AsyncRead + Unpin,
+    server: Arc<Server>,
+    events: mpsc::Sender<Event>,
+) -> Result<()> {
+    let result = handle_messages(tcp_read, server, events.clone()).await;
+    if let Err(e) = &result {
+        // Tell the client why it is disconnected, if it is still there to listen.
+        let error = Message::fatal_error(ErrorKind::Protocol, e.to_string());
+        events.send(Event::Send(error)).await.ok();
+    }
+    result
+}
+
+async fn handle_messages(
+    tcp_read: impl AsyncRead + Unpin,
+    server: Arc<Server>,
+    events: mpsc::Sender<Event>,
+) -> Result<()> {
+    let mut tcp_read = BufReader::new(tcp_read);
+    let Some(mut init_msg) = Codec::Json.read(&mut tcp_read).await? else {
+        return Ok(());
+    };
+    // Clients that do not choose a codec stick to JSON.
+    let mut codec = Codec::Json;
+    if let Message::Codec(chosen) = init_msg {
+        codec = chosen;
+        events.send(Event::Codec(codec)).await?;
+        let Some(msg) = codec.read(&mut tcp_read).await? else {
+            return Ok(());
+        };
+        init_msg = msg;
+    }
+    let Message::User(user) = init_msg.clone() else {
+        bail!(
+            "Expected the initial message to be Message::User, but received: {:?}",
+            init_msg,
+        );
+    };
+    let Some(mut member) = Member::enter(user.clone(), server.clone(), events.clone()).await?
+    else {
+        return Ok(());
+    };
+    let rooms = &server.rooms;
+
+    while let Some(msg) = codec.read(&mut tcp_read).await? {
+        match msg {
+            Message::User(_) => {
+                let error = format!("You are already in the chat as {user}");
+                let error = Message::error(ErrorKind::Username, error);
+                events.send(Event::Send(error)).await?;
+            }
+            // Users can only talk in rooms they have joined.
+            Message::ClientMessage { room, content } if member.joined.contains(&room) => {
+                if server.lag_policy == LagPolicy::Backpressure {
+                    rooms.ready(&room).await;
+                }
+                let chat = Message::Chat {
+                    room: room.clone(),
+                    user: user.clone(),
+                    content,
+                    sent_at: SystemTime::now()
+                        .duration_since(SystemTime::UNIX_EPOCH)?
+                        .as_secs(),
+                };
+                rooms.send(&room, chat);
+            }
+            Message::ClientMessage { room, .. } => {
+                let error = format!("You are not in #{room}, join it first");
+                let error = Message::error(ErrorKind::NotInRoom, error);
+                events.send(Event::Send(error)).await?;
+            }
+            Message::JoinRoom(room) => member.join(room).await?,
+            Message::LeaveRoom(room) => member.leave(room).await?,
+            Message::ListRooms => {
+                events
+                    .send(Event::Send(Message::Rooms(rooms.names())))
+                    .await?
+            }
+            Message::ListUsers => {
+                let users = Message::Users(server.users.names());
+                events.send(Event::Send(users)).await?
+            }
+            Message::Codec(_) => {
+                let error = "The codec can only be chosen in the first message";
+                let error = Message::error(ErrorKind::Protocol, error);
+                events.send(Event::Send(error)).await?;
+            }
+            Message::PrivateMessage { to, content } => {
+                let private = Message::Private {
+                    from: user.clone(),
+                    content,
+                };
+                if let Err(error) = server.users.send(&to, private) {
+                    events.send(Event::Send(error)).await?;
+                }
+            }
+            // Only the server sends these.
+            Message::Chat { .. }
+            | Message::Rooms(_)
+            | Message::Joined { .. }
+            | Message::Left { .. }
+            | Message::Private { .. }
+            | Message::Error { .. }
+            | Message::Missed { .. }
+            | Message::System(_)
+            | Message::Users(_)
+            | Message::Quit(_) => {
+                let error = Message::error(ErrorKind::Protocol, "Only the server sends that");
+                events.send(Event::Send(error)).await?;
+            }
+        };
+    }
+
+    Ok(())
+}
+
+async fn handle_outgoing(
+    tcp_write: &mut (impl AsyncWrite + Unpin),
+    mut events: mpsc::Receiver<Event>,
+    server: &Server,
+) -> Result<()> {
+    let mut rooms = StreamMap::new();
+    let mut codec = Codec::Json;
+    loop {
+        let msg = tokio::select! {
+            // Events go first, so nothing is forwarded from a room after unsubscribing from it.
+            biased;
+            event = events.recv() => match event {
+                Some(Event::Subscribe(room, rx)) => {
+                    rooms.insert(room, BroadcastStream::new(rx));
+                    continue;
+                }
+                Some(Event::Unsubscribe(room)) => {
+                    rooms.remove(&room);
+                    server.caught_up();
+                    continue;
+                }
+                Some(Event::Send(msg)) => msg,
+                Some(Event::Codec(chosen)) => {
+                    codec = chosen;
+                    continue;
+                }
+                None => {
+                    // The connection is over, but what was said before it ended still goes out.
+                    // A zero timeout takes only the messages that are ready, without waiting.
+                    while let Ok(Some((_, Ok(msg)))) =
+                        time::timeout(Duration::ZERO, rooms.next()).await
+                    {
+                        tcp_write.write_all(&codec.encode(&msg)?).await?;
+                    }
+                    return Ok(());
+                }
+            },
+            Some((channel, msg)) = rooms.next() => match msg {
+                Ok(msg) => {
+                    server.caught_up();
+                    msg
+                }
+                Err(BroadcastStreamRecvError::Lagged(count)) => {
+                    if server.lag_policy == LagPolicy::Disconnect {
+                        let error = format!("You fell {count} message(s) behind");
+                        let error = Message::fatal_error(ErrorKind::Lagged, error);
+                        tcp_write.write_all(&codec.encode(&error)?).await?;
+                        return Ok(());
+                    }
+                    let room = match channel {
+                        Channel::Everyone => None,
+                        Channel::Room(room) => Some(room),
+                    };
+                    Message::Missed { room, count }
+                }
+            },
+        };
+        tcp_write.write_all(&codec.encode(&msg)?).await?;
+    }
+}
@@ -0,0 +1,118 @@
+//! Optional TLS for both ends of a connection. Messages are sent the same way over it.
+
+use std::{fs, path::Path, sync::Arc};
+
+use anyhow::{Context, Result};
+use tokio_rustls::{
+    rustls::{
+        crypto::{ring, CryptoProvider},
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+        ClientConfig, RootCertStore, ServerConfig,
+    },
+    TlsAcceptor, TlsConnector,
+};
+
+/// The names a self-signed certificate is valid for
+const LOCAL_NAMES: [&str; 2] = ["localhost", "127.0.0.1"];
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// Accepts TLS connections with a certificate chain and private key read from PEM files
+pub fn acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor> {
+    let chain = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read certificates from {}", cert.display()))?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .with_context(|| format!("Failed to read a private key from {}", key.display()))?;
+    server_acceptor(chain, key)
+}
+
+/// Accepts TLS connections with a certificate for this machine, generated on the spot, for
+/// local development. The certificate is written to `cert`, for clients to trust.
+pub fn self_signed_acceptor(cert: &Path) -> Result<TlsAcceptor> {
+    let generated = rcgen::generate_simple_self_signed(LOCAL_NAMES.map(String::from))?;
+    fs::write(cert, generated.cert.pem())
+        .with_context(|| format!("Failed to write the certificate to {}", cert.display()))?;
+    let key = PrivatePkcs8KeyDer::from(generated.key_pair.serialize_der());
+    server_acceptor(vec![generated.cert.der().clone()], key.into())
+}
+
+fn server_acceptor(
+    chain: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> Result<TlsAcceptor> {
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(chain, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Makes TLS connections to servers with a certificate from one of the usual authorities,
+/// or, if `ca` is given, only from the certificates in that PEM file
+pub fn connector(ca: Option<&Path>) -> Result<TlsConnector> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(ca) => {
+            for cert in CertificateDer::pem_file_iter(ca)
+                .with_context(|| format!("Failed to read certificates from {}", ca.display()))?
+            {
+                roots.add(cert?)?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let config = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::pki_types::ServerName;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = dir.path().join("cert.pem");
+        let acceptor = self_signed_acceptor(&cert).unwrap();
+        let connector = connector(Some(&cert)).unwrap();
+
+        let (client, server) = io::duplex(64 * 1024);
+        let server = tokio::spawn(async move {
+            let mut stream = acceptor.accept(server).await.unwrap();
+            // Only a close_notify from the client ends this.
+            let mut received = Vec::new();
+            stream.read_to_end(&mut received).await.unwrap();
+            stream.write_all(&received).await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+        let name = ServerName::try_from("localhost").unwrap();
+        let mut stream = connector.connect(name, client).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut echoed = Vec::new();
+        stream.read_to_end(&mut echoed).await.unwrap();
+        assert_eq!(echoed, b"hello");
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_self_signed_is_not_trusted_by_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let acceptor = self_signed_acceptor(&dir.path().join("cert.pem")).unwrap();
+        let connector = connector(None).unwrap();
+
+        let (client, server) = io::duplex(64 * 1024);
+        tokio::spawn(async move { acceptor.accept(server).await });
+        let name = ServerName::try_from("localhost").unwrap();
+        assert!(connector.connect(name, client).await.is_err());
+    }
+}
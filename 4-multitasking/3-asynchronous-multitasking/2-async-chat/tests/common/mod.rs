@@ -0,0 +1,110 @@
+//! A client for testing the server without a network, shared by the tests
+#![allow(dead_code)]
+
+use std::{sync::Arc, time::Duration};
+
+use chat::{
+    codec::Codec,
+    history::History,
+    server::{Config, Server},
+    Message, DEFAULT_ROOM,
+};
+use tokio::{
+    io::{self, AsyncWriteExt, BufReader, DuplexStream, ReadHalf, WriteHalf},
+    time,
+};
+
+/// Enough buffer for a client that reads everything it is sent
+pub const BUFFER: usize = 64 * 1024;
+
+pub fn server(config: Config) -> Arc<Server> {
+    Arc::new(Server::new(History::new(0), config))
+}
+
+/// A client connected through an in-memory pipe of `buffer` bytes. A small buffer with nobody
+/// reading from it makes a slow reader, as the server cannot write more than fits in it.
+pub struct Client {
+    read: BufReader<ReadHalf<DuplexStream>>,
+    write: WriteHalf<DuplexStream>,
+}
+
+impl Client {
+    /// Connects without entering the chat
+    pub fn open(server: &Arc<Server>, buffer: usize) -> Self {
+        let (client, connection) = io::duplex(buffer);
+        let (read, write) = io::split(connection);
+        server.connect(read, write);
+        let (read, write) = io::split(client);
+        Client {
+            read: BufReader::new(read),
+            write,
+        }
+    }
+
+    /// Connects and enters the chat, returning once welcomed
+    pub async fn connect(server: &Arc<Server>, user: &str, buffer: usize) -> Self {
+        let mut client = Client::open(server, buffer);
+        client.send(Message::User(user.to_string())).await;
+        // The welcome is the last message of the handshake.
+        client.expect(|msg| matches!(msg, Message::System(_))).await;
+        client
+    }
+
+    pub async fn send(&mut self, msg: Message) {
+        let frame = Codec::Json.encode(&msg).unwrap();
+        self.send_raw(&frame).await;
+    }
+
+    pub async fn send_raw(&mut self, bytes: &[u8]) {
+        self.write.write_all(bytes).await.unwrap();
+    }
+
+    /// The next message, or `None` once the server has closed the connection
+    pub async fn recv(&mut self) -> Option<Message> {
+        time::timeout(Duration::from_secs(5), Codec::Json.read(&mut self.read))
+            .await
+            .expect("timed out waiting for a message")
+            .unwrap()
+    }
+
+    /// Skips messages up to the first one matching `wanted`, which is returned
+    pub async fn expect(&mut self, wanted: impl Fn(&Message) -> bool) -> Message {
+        loop {
+            match self.recv().await {
+                Some(msg) if wanted(&msg) => return msg,
+                Some(_) => {}
+                None => panic!("disconnected"),
+            }
+        }
+    }
+
+    /// Says something in a room, and waits for it to come back
+    pub async fn say_in(&mut self, room: &str, content: &str) {
+        let msg = Message::ClientMessage {
+            room: room.to_string(),
+            content: content.to_string(),
+        };
+        self.send(msg).await;
+        self.expect(|msg| matches!(msg, Message::Chat { content: said, .. } if said == content))
+            .await;
+    }
+
+    /// Says something in the default room, and waits for it to come back
+    pub async fn say(&mut self, content: &str) {
+        self.say_in(DEFAULT_ROOM, content).await;
+    }
+
+    /// Receives messages until the server has answered a `ListUsers`, returning everything
+    /// received before the answer
+    pub async fn sync(&mut self) -> Vec<Message> {
+        self.send(Message::ListUsers).await;
+        let mut received = Vec::new();
+        loop {
+            match self.recv().await {
+                Some(Message::Users(_)) => return received,
+                Some(msg) => received.push(msg),
+                None => panic!("disconnected"),
+            }
+        }
+    }
+}
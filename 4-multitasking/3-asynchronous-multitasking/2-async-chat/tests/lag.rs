@@ -0,0 +1,132 @@
+mod common;
+
+use std::{sync::Arc, time::Duration};
+
+use chat::{
+    server::{Config, LagPolicy, Server},
+    ErrorKind, Message, DEFAULT_ROOM,
+};
+use common::{Client, BUFFER};
+use tokio::{task, time};
+
+/// How many messages the server buffers per channel in these tests
+const CAPACITY: usize = 4;
+
+/// How many messages are said in each test, far more than fit in a channel
+const MESSAGES: usize = 50;
+
+fn server(lag_policy: LagPolicy) -> Arc<Server> {
+    common::server(Config {
+        capacity: CAPACITY,
+        lag_policy,
+        ..Config::default()
+    })
+}
+
+/// Receives messages up to and including the last one said in a test,
+/// returning what was said and how many messages were reported missed
+async fn read_chats(client: &mut Client) -> (Vec<String>, u64) {
+    let (mut chats, mut missed) = (Vec::new(), 0);
+    let last = (MESSAGES - 1).to_string();
+    while !chats.contains(&last) {
+        match client.recv().await {
+            Some(Message::Chat { content, .. }) => chats.push(content),
+            Some(Message::Missed { room, count }) => {
+                assert_eq!(room.as_deref(), Some(DEFAULT_ROOM));
+                missed += count;
+            }
+            Some(_) => {}
+            None => panic!("disconnected"),
+        }
+    }
+    (chats, missed)
+}
+
+#[tokio::test]
+async fn test_drop_notifies_and_keeps_the_connection() {
+    let server = server(LagPolicy::Drop);
+    let mut alice = Client::connect(&server, "alice", BUFFER).await;
+    let mut bob = Client::connect(&server, "bob", 64).await;
+
+    for i in 0..MESSAGES {
+        alice.say(&i.to_string()).await;
+    }
+    let (chats, missed) = read_chats(&mut bob).await;
+    assert!(missed > 0);
+    assert_eq!(chats.len() as u64 + missed, MESSAGES as u64);
+
+    // Bob is still connected.
+    bob.send(Message::ListUsers).await;
+    while !matches!(bob.recv().await, Some(Message::Users(_))) {}
+}
+
+#[tokio::test]
+async fn test_disconnect() {
+    let server = server(LagPolicy::Disconnect);
+    let mut alice = Client::connect(&server, "alice", BUFFER).await;
+    let mut bob = Client::connect(&server, "bob", 64).await;
+
+    for i in 0..MESSAGES {
+        alice.say(&i.to_string()).await;
+    }
+    loop {
+        match bob.recv().await {
+            Some(Message::Error { kind, fatal, .. }) => {
+                assert_eq!(kind, ErrorKind::Lagged);
+                assert!(fatal);
+                break;
+            }
+            Some(_) => {}
+            None => panic!("disconnected without an error"),
+        }
+    }
+    assert!(bob.recv().await.is_none());
+
+    // Bob has left the chat, not just stopped hearing from it.
+    alice.send(Message::ListUsers).await;
+    let users = alice.expect(|msg| matches!(msg, Message::Users(_))).await;
+    assert!(matches!(users, Message::Users(users) if users == ["alice"]));
+}
+
+#[tokio::test]
+async fn test_backpressure() {
+    let server = server(LagPolicy::Backpressure);
+    let mut alice = Client::connect(&server, "alice", BUFFER).await;
+    let mut bob = Client::connect(&server, "bob", 64).await;
+
+    let talking = task::spawn(async move {
+        for i in 0..MESSAGES {
+            alice.say(&i.to_string()).await;
+        }
+    });
+    // Alice has to wait for Bob, who is not reading yet.
+    time::sleep(Duration::from_millis(200)).await;
+    assert!(!talking.is_finished());
+
+    let (chats, missed) = read_chats(&mut bob).await;
+    assert_eq!(missed, 0);
+    let expected: Vec<String> = (0..MESSAGES).map(|i| i.to_string()).collect();
+    assert_eq!(chats, expected);
+    talking.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_backpressure_released_when_the_slow_reader_leaves() {
+    let server = server(LagPolicy::Backpressure);
+    let mut alice = Client::connect(&server, "alice", BUFFER).await;
+    let bob = Client::connect(&server, "bob", 64).await;
+
+    let talking = task::spawn(async move {
+        for i in 0..MESSAGES {
+            alice.say(&i.to_string()).await;
+        }
+    });
+    time::sleep(Duration::from_millis(200)).await;
+    assert!(!talking.is_finished());
+
+    drop(bob);
+    time::timeout(Duration::from_secs(5), talking)
+        .await
+        .expect("Alice is still held up")
+        .unwrap();
+}
@@ -0,0 +1,208 @@
+mod common;
+
+use chat::{server::Config, ErrorKind, Message, DEFAULT_ROOM};
+use common::{Client, BUFFER};
+
+fn is_error(kind: ErrorKind) -> impl Fn(&Message) -> bool {
+    move |msg| matches!(msg, Message::Error { kind: got, .. } if *got == kind)
+}
+
+/// Checks that the server sends a fatal error of some kind, then closes the connection
+async fn expect_fatal(client: &mut Client, kind: ErrorKind) {
+    let error = client.expect(is_error(kind)).await;
+    assert!(matches!(error, Message::Error { fatal: true, .. }));
+    while let Some(msg) = client.recv().await {
+        assert!(!matches!(msg, Message::System(_)), "entered the chat");
+    }
+}
+
+#[tokio::test]
+async fn test_rooms_are_isolated() {
+    let server = common::server(Config::default());
+    let mut alice = Client::connect(&server, "alice", BUFFER).await;
+    let mut bob = Client::connect(&server, "bob", BUFFER).await;
+
+    bob.send(Message::JoinRoom("rust".to_string())).await;
+    bob.expect(|msg| matches!(msg, Message::Joined { room, .. } if room == "rust"))
+        .await;
+    bob.say_in("rust", "only for #rust").await;
+
+    // Alice is in the default room only.
+    let received = alice.sync().await;
+    assert!(!received
+        .iter()
+        .any(|msg| matches!(msg, Message::Chat { room, .. } if room == "rust")));
+    alice
+        .send(Message::ClientMessage {
+            room: "rust".to_string(),
+            content: "hello?".to_string(),
+        })
+        .await;
+    alice.expect(is_error(ErrorKind::NotInRoom)).await;
+
+    alice.send(Message::ListRooms).await;
+    let rooms = alice.expect(|msg| matches!(msg, Message::Rooms(_))).await;
+    assert!(
+        matches!(rooms, Message::Rooms(rooms) if rooms == [DEFAULT_ROOM.to_string(), "rust".to_string()])
+    );
+
+    // Leaving a room is confirmed, and takes away the right to talk in it.
+    bob.send(Message::LeaveRoom("rust".to_string())).await;
+    bob.expect(|msg| matches!(msg, Message::Left { room, .. } if room == "rust"))
+        .await;
+    bob.send(Message::ClientMessage {
+        room: "rust".to_string(),
+        content: "still here?".to_string(),
+    })
+    .await;
+    bob.expect(is_error(ErrorKind::NotInRoom)).await;
+
+    // Nobody is left in it, so it is gone.
+    alice.send(Message::ListRooms).await;
+    let rooms = alice.expect(|msg| matches!(msg, Message::Rooms(_))).await;
+    assert!(matches!(rooms, Message::Rooms(rooms) if rooms == [DEFAULT_ROOM.to_string()]));
+}
+
+#[tokio::test]
+async fn test_who_is_online() {
+    let server = common::server(Config::default());
+    let mut alice = Client::connect(&server, "alice", BUFFER).await;
+    let _bob = Client::connect(&server, "bob", BUFFER).await;
+
+    alice.send(Message::ListUsers).await;
+    let users = alice.expect(|msg| matches!(msg, Message::Users(_))).await;
+    assert!(matches!(users, Message::Users(users) if users == ["alice", "bob"]));
+}
+
+#[tokio::test]
+async fn test_duplicate_username() {
+    let server = common::server(Config::default());
+    let _alice = Client::connect(&server, "alice", BUFFER).await;
+
+    let mut impostor = Client::open(&server, BUFFER);
+    impostor.send(Message::User("alice".to_string())).await;
+    expect_fatal(&mut impostor, ErrorKind::Username).await;
+
+    let mut nameless = Client::open(&server, BUFFER);
+    nameless.send(Message::User(String::new())).await;
+    expect_fatal(&mut nameless, ErrorKind::Username).await;
+}
+
+#[tokio::test]
+async fn test_quit_on_disconnect() {
+    let server = common::server(Config::default());
+    let mut alice = Client::connect(&server, "alice", BUFFER).await;
+    let bob = Client::connect(&server, "bob", BUFFER).await;
+
+    drop(bob);
+    alice
+        .expect(|msg| matches!(msg, Message::Quit(user) if user == "bob"))
+        .await;
+    alice.send(Message::ListUsers).await;
+    let users = alice.expect(|msg| matches!(msg, Message::Users(_))).await;
+    assert!(matches!(users, Message::Users(users) if users == ["alice"]));
+
+    // The name is free again.
+    Client::connect(&server, "bob", BUFFER).await;
+}
+
+#[tokio::test]
+async fn test_bad_first_message() {
+    let server = common::server(Config::default());
+
+    let mut client = Client::open(&server, BUFFER);
+    client.send(Message::ListRooms).await;
+    expect_fatal(&mut client, ErrorKind::Protocol).await;
+
+    let mut client = Client::open(&server, BUFFER);
+    client.send_raw(b"not json\n").await;
+    expect_fatal(&mut client, ErrorKind::Protocol).await;
+}
+
+#[tokio::test]
+async fn test_private_messages() {
+    let server = common::server(Config::default());
+    let mut alice = Client::connect(&server, "alice", BUFFER).await;
+    let mut bob = Client::connect(&server, "bob", BUFFER).await;
+
+    alice
+        .send(Message::PrivateMessage {
+            to: "bob".to_string(),
+            content: "psst".to_string(),
+        })
+        .await;
+    let private = bob
+        .expect(|msg| matches!(msg, Message::Private { .. }))
+        .await;
+    assert!(
+        matches!(private, Message::Private { from, content } if from == "alice" && content == "psst")
+    );
+
+    alice
+        .send(Message::PrivateMessage {
+            to: "carol".to_string(),
+            content: "anyone?".to_string(),
+        })
+        .await;
+    let error = alice.expect(is_error(ErrorKind::UserOffline)).await;
+    assert!(matches!(error, Message::Error { fatal: false, .. }));
+}
+
+#[tokio::test]
+async fn test_private_messages_to_a_slow_reader() {
+    let server = common::server(Config::default());
+    let mut alice = Client::connect(&server, "alice", BUFFER).await;
+    // Bob never reads, and his pipe is too small for even one message.
+    let _bob = Client::connect(&server, "bob", 64).await;
+
+    // Once Bob's queue is full, Alice is told so instead of being held up.
+    for sent in 0.. {
+        assert!(sent < 100, "never refused");
+        alice
+            .send(Message::PrivateMessage {
+                to: "bob".to_string(),
+                content: sent.to_string(),
+            })
+            .await;
+        let received = alice.sync().await;
+        if let Some(Message::Error { kind, fatal, .. }) = received.last() {
+            assert_eq!(*kind, ErrorKind::UserBusy);
+            assert!(!fatal);
+            break;
+        }
+    }
+    alice.say("still here").await;
+}
+
+#[tokio::test]
+async fn test_max_clients() {
+    let server = common::server(Config {
+        max_clients: Some(2),
+        ..Config::default()
+    });
+    let _alice = Client::connect(&server, "alice", BUFFER).await;
+    // Connections count before they enter the chat.
+    let idle = Client::open(&server, BUFFER);
+
+    let mut bob = Client::open(&server, BUFFER);
+    bob.send(Message::User("bob".to_string())).await;
+    expect_fatal(&mut bob, ErrorKind::Full).await;
+
+    // The connection is closed once the idle client goes, freeing its place.
+    drop(idle);
+    for _ in 0..100 {
+        let mut bob = Client::open(&server, BUFFER);
+        bob.send(Message::User("bob".to_string())).await;
+        match bob.recv().await {
+            Some(Message::Error {
+                kind: ErrorKind::Full,
+                ..
+            }) => {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+            Some(_) => return,
+            None => panic!("disconnected"),
+        }
+    }
+    panic!("the idle client still counts");
+}
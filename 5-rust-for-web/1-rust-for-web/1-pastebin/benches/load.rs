@@ -0,0 +1,143 @@
+//! Measures throughput with many concurrent clients storing and loading pastes over HTTP,
+//! with the sharded storage and again with every storage call behind a single lock, as
+//! before storages were sharded.
+//!
+//! Run with `cargo bench --bench load`; set `CLIENTS` and `SECONDS` to change the load.
+
+use std::{
+    env,
+    io::{self, Read},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use pastebin::{
+    api::StoreOptions,
+    app::{self, AppState},
+    client::Client,
+    config::Limits,
+    paste::{BodyReader, Metadata, Paste, Revision},
+    storage::{MemoryStorage, Staged, Storage},
+};
+use uuid::Uuid;
+
+/// A storage that handles one call at a time.
+struct SingleLock<S>(Mutex<S>);
+
+impl<S: Storage> Storage for SingleLock<S> {
+    // Bodies used to be received in full before the lock was taken.
+    fn stage(&self, body: &mut dyn Read, compress_above: Option<usize>) -> io::Result<Staged> {
+        let mut buf = Vec::new();
+        body.read_to_end(&mut buf)?;
+        self.0.lock().unwrap().stage(&mut &buf[..], compress_above)
+    }
+
+    fn insert(&self, uuid: Uuid, meta: Metadata, body: Staged) -> io::Result<()> {
+        self.0.lock().unwrap().insert(uuid, meta, body)
+    }
+
+    fn add_revision(
+        &self,
+        uuid: &Uuid,
+        revision: Revision,
+        body: Staged,
+    ) -> io::Result<Option<u32>> {
+        self.0.lock().unwrap().add_revision(uuid, revision, body)
+    }
+
+    fn open(
+        &self,
+        uuid: &Uuid,
+        revision: Option<u32>,
+    ) -> io::Result<Option<Paste<Box<dyn BodyReader>>>> {
+        self.0.lock().unwrap().open(uuid, revision)
+    }
+
+    fn meta(&self, uuid: &Uuid) -> io::Result<Option<Metadata>> {
+        self.0.lock().unwrap().meta(uuid)
+    }
+
+    fn remove(&self, uuid: &Uuid) -> io::Result<Option<Metadata>> {
+        self.0.lock().unwrap().remove(uuid)
+    }
+
+    fn remove_expired(&self, now: SystemTime) -> io::Result<usize> {
+        self.0.lock().unwrap().remove_expired(now)
+    }
+
+    fn total_bytes(&self) -> u64 {
+        self.0.lock().unwrap().total_bytes()
+    }
+
+    fn list(&self) -> io::Result<Vec<(Uuid, Metadata)>> {
+        self.0.lock().unwrap().list()
+    }
+}
+
+fn env_or(name: &str, default: u64) -> u64 {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+#[tokio::main]
+async fn main() {
+    let clients = env_or("CLIENTS", 64);
+    let duration = Duration::from_secs(env_or("SECONDS", 5));
+
+    run(
+        "sharded",
+        Box::<MemoryStorage>::default(),
+        clients,
+        duration,
+    )
+    .await;
+    let single_lock = SingleLock(Mutex::new(MemoryStorage::default()));
+    run("single lock", Box::new(single_lock), clients, duration).await;
+}
+
+async fn run(name: &str, storage: Box<dyn Storage>, clients: u64, duration: Duration) {
+    let limits = Limits::default();
+    let app = app::router(AppState::new(storage, &limits), &limits);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let requests = Arc::new(AtomicU64::new(0));
+    let workers: Vec<_> = (0..clients)
+        .map(|i| {
+            let client = Client::new(&server);
+            let (stop, requests) = (stop.clone(), requests.clone());
+            tokio::spawn(async move {
+                let options = StoreOptions::default();
+                while !stop.load(Ordering::Relaxed) {
+                    let body = format!("paste from client {i}");
+                    let response = client.store(body, "text/plain", &options).await.unwrap();
+                    // Reads outnumber writes on a pastebin.
+                    for _ in 0..4 {
+                        client.load(&response.uuid, None).await.unwrap();
+                    }
+                    requests.fetch_add(5, Ordering::Relaxed);
+                }
+            })
+        })
+        .collect();
+
+    let start = Instant::now();
+    tokio::time::sleep(duration).await;
+    stop.store(true, Ordering::Relaxed);
+    for worker in workers {
+        worker.await.unwrap();
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    let requests = requests.load(Ordering::Relaxed);
+    println!(
+        "{name}, {clients} clients: {requests} requests in {elapsed:.1}s, {:.0} requests/s",
+        requests as f64 / elapsed
+    );
+}
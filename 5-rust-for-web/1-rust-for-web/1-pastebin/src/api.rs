@@ -0,0 +1,62 @@
+//! Types exchanged between the pastebin server and its clients.
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// The request header carrying the secret that allows a paste to be updated or deleted.
+pub const DELETE_TOKEN_HEADER: &str = "x-delete-token";
+
+/// The header carrying the nonce of an end-to-end encrypted paste, both when it is stored and
+/// when it is loaded. The server stores such pastes as opaque blobs.
+pub const NONCE_HEADER: &str = "x-paste-nonce";
+
+/// The query parameters of `POST /store`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StoreOptions {
+    /// Time-to-live in seconds.
+    pub ttl: Option<u64>,
+    /// Delete the paste as soon as it has been loaded once.
+    #[serde(default)]
+    pub burn: bool,
+    pub title: Option<String>,
+}
+
+/// The response to `POST /store`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoreResponse {
+    pub uuid: Uuid,
+    /// Must be sent in the [`DELETE_TOKEN_HEADER`] header to update or delete the paste.
+    pub token: String,
+}
+
+/// The response to `PUT /update/:uuid`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UpdateResponse {
+    /// The number of the revision that was created; the original paste is revision 0.
+    pub revision: u32,
+}
+
+/// Public information about a paste, as returned by `GET /list` and `GET /search`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PasteInfo {
+    pub uuid: Uuid,
+    pub title: Option<String>,
+    /// The content type of the latest revision.
+    pub content_type: String,
+    /// The size of the latest revision in bytes.
+    pub size: u64,
+    /// How many revisions the paste has.
+    pub revisions: u32,
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+    /// Seconds since the Unix epoch.
+    pub expires_at: Option<u64>,
+}
+
+/// One page of pastes, newest first.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PasteList {
+    /// How many pastes there are across all pages.
+    pub total: usize,
+    pub pastes: Vec<PasteInfo>,
+}
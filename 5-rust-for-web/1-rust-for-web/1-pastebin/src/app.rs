@@ -0,0 +1,751 @@
+use std::{
+    fmt,
+    io::{self, Read, Seek, SeekFrom},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{Html, IntoResponse, Response},
+    routing::{delete, get, post, put},
+    Json, Router,
+};
+use futures_util::{stream, StreamExt};
+use serde::Deserialize;
+use similar::TextDiff;
+use tokio::sync::mpsc;
+use tokio_util::io::{StreamReader, SyncIoBridge};
+use tower_http::trace::TraceLayer;
+use uuid::Uuid;
+
+use crate::{
+    api::{
+        PasteInfo, PasteList, StoreOptions, StoreResponse, UpdateResponse, DELETE_TOKEN_HEADER,
+        NONCE_HEADER,
+    },
+    compress,
+    config::Limits,
+    crypto::is_valid_nonce,
+    metrics::{Metrics, MetricsLayer},
+    paste::{BodyReader, Encoding, Metadata, Paste, Revision, DEFAULT_CONTENT_TYPE},
+    range,
+    rate_limit::RateLimitLayer,
+    render::render_html,
+    storage::{Staged, Storage},
+};
+
+/// How often expired pastes are purged from the store.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How many bytes of a paste are read at a time while it is sent.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub type SharedState = Arc<AppState>;
+
+/// Everything the handlers share. There is no lock around it: the storage locks only the
+/// pastes it touches, so requests for different pastes run in parallel.
+pub struct AppState {
+    pub store: Box<dyn Storage>,
+    max_paste_size: usize,
+    store_quota: u64,
+    /// Bytes set aside for bodies that are about to be stored; see [`AppState::reserve`].
+    reserved: AtomicU64,
+    compress_above: usize,
+    metrics: Arc<Metrics>,
+    /// Cleared once the server starts shutting down.
+    pub ready: AtomicBool,
+}
+
+impl AppState {
+    pub fn new(store: Box<dyn Storage>, limits: &Limits) -> SharedState {
+        Arc::new(AppState {
+            store,
+            max_paste_size: limits.max_paste_size,
+            store_quota: limits.store_quota,
+            reserved: AtomicU64::new(0),
+            compress_above: limits.compress_above,
+            metrics: Arc::new(Metrics::default()),
+            ready: AtomicBool::new(true),
+        })
+    }
+
+    /// Sets room aside in the store for a body of `len` bytes, or fails if that would exceed
+    /// the quota. The room is given back when the reservation is dropped, by which time the
+    /// body is either stored or not.
+    fn reserve(&self, len: u64) -> Result<Reservation<'_>, AppError> {
+        // Reserving before checking means that of two concurrent requests, the later one sees
+        // the earlier one's bytes, either reserved or stored. A body that is stored but not yet
+        // given back is counted twice, which only errs on the side of refusing.
+        let reserved = self.reserved.fetch_add(len, Ordering::SeqCst);
+        let reservation = Reservation {
+            reserved: &self.reserved,
+            len,
+        };
+        if self.store.total_bytes() + reserved + len > self.store_quota {
+            return Err(AppError::QuotaExceeded);
+        }
+        Ok(reservation)
+    }
+}
+
+/// Room in the store set aside by [`AppState::reserve`].
+struct Reservation<'a> {
+    reserved: &'a AtomicU64,
+    len: u64,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.reserved.fetch_sub(self.len, Ordering::SeqCst);
+    }
+}
+
+/// Builds the pastebin app on top of `state`.
+///
+/// Serve it with [`Router::into_make_service_with_connect_info`] for rate limiting to apply.
+pub fn router(state: SharedState, limits: &Limits) -> Router {
+    let metrics = state.metrics.clone();
+    Router::new()
+        .route("/store", post(store))
+        .route("/load/:uuid", get(load))
+        .route("/view/:uuid", get(view))
+        .route("/update/:uuid", put(update))
+        .route("/diff/:uuid", get(diff))
+        .route("/delete/:uuid", delete(delete_))
+        .route("/list", get(list))
+        .route("/search", get(search))
+        .route("/healthz", get(healthz))
+        .route("/metrics", get(metrics_))
+        .with_state(state)
+        .layer(RateLimitLayer::new(
+            limits.rate_limit_burst,
+            limits.rate_limit_period,
+        ))
+        .layer(MetricsLayer::new(metrics))
+        .layer(TraceLayer::new_for_http())
+}
+
+/// Periodically removes expired pastes from the store. Never returns.
+pub async fn purge_expired(state: SharedState) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let purging = state.clone();
+        let removed =
+            tokio::task::spawn_blocking(move || purging.store.remove_expired(SystemTime::now()));
+        match removed
+            .await
+            .map_err(io::Error::other)
+            .and_then(|removed| removed)
+        {
+            Ok(0) => {}
+            Ok(n) => tracing::debug!("purged {n} expired pastes"),
+            Err(e) => tracing::error!("failed to purge expired pastes: {e}"),
+        }
+    }
+}
+
+/// Everything that can go wrong while handling a request.
+enum AppError {
+    /// The request is malformed in a way the extractors do not catch.
+    BadRequest(&'static str),
+    NotFound,
+    /// The paste existed but has expired.
+    Gone,
+    Forbidden,
+    /// The paste is not text, so it cannot be rendered.
+    NotText,
+    /// The paste is larger than the largest paste accepted.
+    TooLarge,
+    /// Storing the paste would exceed the store quota.
+    QuotaExceeded,
+    /// The requested range starts beyond the end of a body of this many bytes.
+    RangeNotSatisfiable(u64),
+    Storage(io::Error),
+}
+
+impl From<io::Error> for AppError {
+    fn from(e: io::Error) -> Self {
+        AppError::Storage(e)
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        match self {
+            AppError::BadRequest(msg) => return (StatusCode::BAD_REQUEST, msg).into_response(),
+            AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::Gone => StatusCode::GONE,
+            AppError::Forbidden => StatusCode::FORBIDDEN,
+            AppError::NotText => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::QuotaExceeded => StatusCode::INSUFFICIENT_STORAGE,
+            AppError::RangeNotSatisfiable(len) => {
+                let content_range = format!("bytes */{len}");
+                return (
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    [(header::CONTENT_RANGE, content_range)],
+                )
+                    .into_response();
+            }
+            AppError::Storage(e) => {
+                tracing::error!("storage error: {e}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+        .into_response()
+    }
+}
+
+async fn store(
+    State(state): State<SharedState>,
+    Query(params): Query<StoreOptions>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Json<StoreResponse>, AppError> {
+    let uuid = Uuid::new_v4();
+    let upload = Upload::new(&headers)?;
+    let body = receive(&state, &headers, body, &upload).await?;
+    let _reservation = state.reserve(body.stored_len)?;
+    let mut meta = Metadata::new(upload.revision(&body));
+    meta.expires_at = params
+        .ttl
+        .map(|ttl| SystemTime::now() + Duration::from_secs(ttl));
+    meta.burn_after_read = params.burn;
+    meta.title = params.title;
+    let token = meta.delete_token.clone();
+    blocking(&state, move |state| {
+        Ok(state.store.insert(uuid, meta, body)?)
+    })
+    .await?;
+    Ok(Json(StoreResponse { uuid, token }))
+}
+
+/// What the headers of a `/store` or `/update` request say about the body.
+struct Upload {
+    content_type: String,
+    nonce: Option<String>,
+}
+
+impl Upload {
+    fn new(headers: &HeaderMap) -> Result<Self, AppError> {
+        let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+        let content_type = header(header::CONTENT_TYPE.as_str()).unwrap_or(DEFAULT_CONTENT_TYPE);
+        let nonce = match header(NONCE_HEADER) {
+            Some(nonce) if !is_valid_nonce(nonce) => {
+                return Err(AppError::BadRequest("malformed nonce"))
+            }
+            nonce => nonce.map(str::to_string),
+        };
+        Ok(Upload {
+            content_type: content_type.to_string(),
+            nonce,
+        })
+    }
+
+    /// Describes a body received with these headers as a new revision.
+    fn revision(&self, body: &Staged) -> Revision {
+        let mut revision = body.revision(&self.content_type);
+        revision.nonce.clone_from(&self.nonce);
+        revision
+    }
+}
+
+/// A request body grew beyond the largest paste accepted.
+#[derive(Debug)]
+struct TooLarge;
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("paste too large")
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+/// Stages a request body in the store as it arrives, so that it never has to be held in
+/// memory as a whole.
+async fn receive(
+    state: &SharedState,
+    headers: &HeaderMap,
+    body: Body,
+    upload: &Upload,
+) -> Result<Staged, AppError> {
+    let max = state.max_paste_size;
+    let content_length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse::<usize>().ok());
+    if content_length.is_some_and(|len| len > max) {
+        return Err(AppError::TooLarge);
+    }
+    let mut received = 0;
+    let chunks = body.into_data_stream().map(move |chunk| {
+        let chunk = chunk.map_err(io::Error::other)?;
+        received += chunk.len();
+        if received > max {
+            return Err(io::Error::other(TooLarge));
+        }
+        Ok(chunk)
+    });
+    let mut reader = SyncIoBridge::new(StreamReader::new(chunks));
+    // Ciphertext does not compress.
+    let compress_above = upload.nonce.is_none().then_some(state.compress_above);
+    let state = state.clone();
+    let staged =
+        tokio::task::spawn_blocking(move || state.store.stage(&mut reader, compress_above))
+            .await
+            .map_err(io::Error::other)?;
+    staged.map_err(|e| match e.get_ref() {
+        Some(inner) if inner.is::<TooLarge>() => AppError::TooLarge,
+        Some(inner) if inner.is::<axum::Error>() => {
+            AppError::BadRequest("failed to read the request body")
+        }
+        _ => AppError::Storage(e),
+    })
+}
+
+/// Looks up a paste for reading, enforcing its expiry and burn-after-read rules.
+fn read_paste(
+    state: &AppState,
+    uuid: &Uuid,
+    revision: Option<u32>,
+) -> Result<Paste<Box<dyn BodyReader>>, AppError> {
+    let paste = state
+        .store
+        .open(uuid, revision)?
+        .ok_or(AppError::NotFound)?;
+    if paste.meta.is_expired(SystemTime::now()) {
+        state.store.remove(uuid)?;
+        return Err(AppError::Gone);
+    }
+    // Only the reader that gets to remove the paste may see it.
+    if paste.meta.burn_after_read && state.store.remove(uuid)?.is_none() {
+        return Err(AppError::NotFound);
+    }
+    Ok(paste)
+}
+
+/// Runs `f` on a blocking thread, as storages may read and write files.
+async fn blocking<T, F>(state: &SharedState, f: F) -> Result<T, AppError>
+where
+    T: Send + 'static,
+    F: FnOnce(&AppState) -> Result<T, AppError> + Send + 'static,
+{
+    let state = state.clone();
+    tokio::task::spawn_blocking(move || f(&state))
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// Sends what `body` reads as a response body, reading it on a blocking thread. The first
+/// `skip` bytes are read but not sent.
+fn stream_body(mut body: Box<dyn Read + Send>, skip: u64) -> Body {
+    let (tx, rx) = mpsc::channel::<io::Result<Bytes>>(4);
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = io::copy(&mut (&mut body).take(skip), &mut io::sink()) {
+            let _ = tx.blocking_send(Err(e));
+            return;
+        }
+        loop {
+            let mut chunk = vec![0; CHUNK_SIZE];
+            let chunk = match body.read(&mut chunk) {
+                Ok(0) => return,
+                Ok(n) => {
+                    chunk.truncate(n);
+                    Ok(Bytes::from(chunk))
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    tracing::error!("failed to read a stored body: {e}");
+                    Err(e)
+                }
+            };
+            let failed = chunk.is_err();
+            // Sending fails once the client has gone away.
+            if tx.blocking_send(chunk).is_err() || failed {
+                return;
+            }
+        }
+    });
+    Body::from_stream(stream::unfold(rx, |mut rx| async move {
+        Some((rx.recv().await?, rx))
+    }))
+}
+
+#[derive(Deserialize)]
+struct LoadParams {
+    /// The revision to load instead of the latest one.
+    rev: Option<u32>,
+}
+
+async fn load(
+    State(state): State<SharedState>,
+    Path(uuid): Path<Uuid>,
+    Query(params): Query<LoadParams>,
+    request_headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let request_header = |name| {
+        request_headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    let range = request_header(header::RANGE).map(str::to_string);
+    let accepts_gzip = request_header(header::ACCEPT_ENCODING).is_some_and(compress::accepts_gzip);
+    // Seeking into the body reads its file, just like opening it.
+    blocking(&state, move |state| {
+        let paste = read_paste(state, &uuid, params.rev)?;
+        load_response(paste, range.as_deref(), accepts_gzip)
+    })
+    .await
+}
+
+/// Responds to `/load` with `paste`, or the part of it `range` asks for.
+fn load_response(
+    paste: Paste<Box<dyn BodyReader>>,
+    range: Option<&str>,
+    accepts_gzip: bool,
+) -> Result<Response, AppError> {
+    // Ranges are of the body as sent without `Content-Encoding`, whose length is known.
+    let size = paste.current().size;
+    // A burnt paste cannot be loaded again for the rest of it, so it is sent whole.
+    let burn = paste.meta.burn_after_read;
+    let range = match range {
+        Some(range) if !burn => {
+            range::parse(range, size).map_err(|_| AppError::RangeNotSatisfiable(size))?
+        }
+        _ => None,
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+    headers.insert(
+        header::ACCEPT_RANGES,
+        HeaderValue::from_static(if burn { "none" } else { "bytes" }),
+    );
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_str(&paste.current().content_type)
+            .unwrap_or(HeaderValue::from_static(DEFAULT_CONTENT_TYPE)),
+    );
+    // Whoever stored the paste chose its content type, so an HTML paste must not be able to
+    // run scripts on our origin, nor any paste be sniffed as HTML.
+    headers.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    headers.insert(
+        header::CONTENT_SECURITY_POLICY,
+        HeaderValue::from_static("sandbox"),
+    );
+    if let Some(nonce) = &paste.current().nonce {
+        headers.insert(NONCE_HEADER, HeaderValue::from_str(nonce).unwrap());
+    }
+    let Paste {
+        mut body, encoding, ..
+    } = paste;
+    let (status, len, body) = match range {
+        Some(range) => {
+            headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes {}-{}/{size}", range.start, range.end - 1))
+                    .unwrap(),
+            );
+            let len = range.end - range.start;
+            let body = match encoding {
+                Encoding::Identity => {
+                    body.seek(SeekFrom::Start(range.start))?;
+                    stream_body(Box::new(body.take(len)), 0)
+                }
+                // Compressed bodies cannot be seeked into, only read up to the range.
+                Encoding::Gzip => {
+                    let body = compress::decoder(body, encoding).take(range.end);
+                    stream_body(Box::new(body), range.start)
+                }
+            };
+            (StatusCode::PARTIAL_CONTENT, len, body)
+        }
+        // Sent as stored, without inflating it first.
+        None if encoding == Encoding::Gzip && accepts_gzip => {
+            headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+            let len = body.seek(SeekFrom::End(0))?;
+            body.rewind()?;
+            (StatusCode::OK, len, stream_body(body, 0))
+        }
+        None => (
+            StatusCode::OK,
+            size,
+            stream_body(compress::decoder(body, encoding), 0),
+        ),
+    };
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(len));
+    Ok((status, headers, body).into_response())
+}
+
+#[derive(Deserialize)]
+struct ViewParams {
+    /// The language to highlight the paste as, e.g. `rust`.
+    lang: Option<String>,
+    /// The revision to view instead of the latest one.
+    rev: Option<u32>,
+}
+
+async fn view(
+    State(state): State<SharedState>,
+    Path(uuid): Path<Uuid>,
+    Query(params): Query<ViewParams>,
+) -> Result<Html<String>, AppError> {
+    let paste = blocking(&state, move |state| {
+        Ok(read_paste(state, &uuid, params.rev)?.read()?.decode()?)
+    })
+    .await?;
+    let text = paste.text().ok_or(AppError::NotText)?;
+    Ok(Html(render_html(
+        &uuid.to_string(),
+        text,
+        params.lang.as_deref(),
+    )))
+}
+
+/// Checks that the request carries the paste's delete token.
+fn check_token(meta: &Metadata, headers: &HeaderMap) -> Result<(), AppError> {
+    let token = headers
+        .get(DELETE_TOKEN_HEADER)
+        .and_then(|token| token.to_str().ok())
+        .unwrap_or_default();
+    if meta.can_delete(token) {
+        Ok(())
+    } else {
+        Err(AppError::Forbidden)
+    }
+}
+
+async fn update(
+    State(state): State<SharedState>,
+    Path(uuid): Path<Uuid>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Json<UpdateResponse>, AppError> {
+    let upload = Upload::new(&headers)?;
+    // Checked before the body is received, so that nobody can upload to a paste they cannot
+    // update.
+    let meta = blocking(&state, move |state| {
+        state.store.meta(&uuid)?.ok_or(AppError::NotFound)
+    })
+    .await?;
+    check_token(&meta, &headers)?;
+    if meta.is_expired(SystemTime::now()) {
+        return Err(AppError::Gone);
+    }
+    let body = receive(&state, &headers, body, &upload).await?;
+    let _reservation = state.reserve(body.stored_len)?;
+    let revision = upload.revision(&body);
+    let revision = blocking(&state, move |state| {
+        Ok(state.store.add_revision(&uuid, revision, body)?)
+    })
+    .await?
+    .ok_or(AppError::NotFound)?;
+    Ok(Json(UpdateResponse { revision }))
+}
+
+#[derive(Deserialize)]
+struct DiffParams {
+    /// Defaults to the revision before `to`.
+    from: Option<u32>,
+    /// Defaults to the latest revision.
+    to: Option<u32>,
+}
+
+async fn diff(
+    State(state): State<SharedState>,
+    Path(uuid): Path<Uuid>,
+    Query(params): Query<DiffParams>,
+) -> Result<String, AppError> {
+    let (from, to) = blocking(&state, move |state| {
+        let meta = state.store.meta(&uuid)?.ok_or(AppError::NotFound)?;
+        let from = match params.from {
+            Some(from) => from,
+            None => params.to.unwrap_or(meta.latest()).saturating_sub(1),
+        };
+        // Read first, as reading a burn-after-read paste removes every revision of it.
+        let from = state
+            .store
+            .get(&uuid, Some(from))?
+            .ok_or(AppError::NotFound)?;
+        let to = read_paste(state, &uuid, params.to)?.read()?.decode()?;
+        Ok((from.decode()?, to))
+    })
+    .await?;
+    let (Some(old), Some(new)) = (from.text(), to.text()) else {
+        return Err(AppError::NotText);
+    };
+    Ok(TextDiff::from_lines(old, new)
+        .unified_diff()
+        .header(
+            &format!("revision {}", from.revision),
+            &format!("revision {}", to.revision),
+        )
+        .to_string())
+}
+
+async fn delete_(
+    State(state): State<SharedState>,
+    Path(uuid): Path<Uuid>,
+    headers: HeaderMap,
+) -> Result<(), AppError> {
+    blocking(&state, move |state| {
+        let meta = state.store.meta(&uuid)?.ok_or(AppError::NotFound)?;
+        check_token(&meta, &headers)?;
+        state.store.remove(&uuid)?;
+        Ok(())
+    })
+    .await
+}
+
+/// The most pastes returned in one page of `/list` or `/search`.
+const MAX_PAGE_SIZE: usize = 100;
+
+fn default_page_size() -> usize {
+    20
+}
+
+#[derive(Deserialize)]
+struct ListParams {
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "default_page_size")]
+    limit: usize,
+}
+
+#[derive(Deserialize)]
+struct SearchParams {
+    /// Matched case-insensitively against titles and text contents.
+    q: String,
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "default_page_size")]
+    limit: usize,
+}
+
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Sorts `pastes` newest first and cuts out the requested page.
+fn paginate(mut pastes: Vec<(Uuid, Metadata)>, offset: usize, limit: usize) -> PasteList {
+    pastes.sort_by_key(|(_, meta)| std::cmp::Reverse(meta.created_at));
+    PasteList {
+        total: pastes.len(),
+        pastes: pastes
+            .into_iter()
+            .skip(offset)
+            .take(limit.min(MAX_PAGE_SIZE))
+            .map(|(uuid, meta)| PasteInfo {
+                uuid,
+                content_type: meta.latest_revision().content_type.clone(),
+                size: meta.latest_revision().size,
+                revisions: meta.revisions.len() as u32,
+                title: meta.title,
+                created_at: unix_secs(meta.created_at),
+                expires_at: meta.expires_at.map(unix_secs),
+            })
+            .collect(),
+    }
+}
+
+async fn list(
+    State(state): State<SharedState>,
+    Query(params): Query<ListParams>,
+) -> Result<Json<PasteList>, AppError> {
+    let now = SystemTime::now();
+    let mut pastes = state.store.list()?;
+    pastes.retain(|(_, meta)| meta.is_listed(now));
+    Ok(Json(paginate(pastes, params.offset, params.limit)))
+}
+
+async fn search(
+    State(state): State<SharedState>,
+    Query(params): Query<SearchParams>,
+) -> Result<Json<PasteList>, AppError> {
+    let (offset, limit) = (params.offset, params.limit);
+    let matches = blocking(&state, move |state| {
+        let now = SystemTime::now();
+        let query = params.q.to_lowercase();
+        let mut matches = Vec::new();
+        for (uuid, meta) in state.store.list()? {
+            if !meta.is_listed(now) {
+                continue;
+            }
+            let title_matches = meta
+                .title
+                .as_ref()
+                .is_some_and(|title| title.to_lowercase().contains(&query));
+            let text_matches = || -> io::Result<bool> {
+                let Some(paste) = state.store.get(&uuid, None)? else {
+                    return Ok(false);
+                };
+                Ok(paste
+                    .decode()?
+                    .text()
+                    .is_some_and(|text| text.to_lowercase().contains(&query)))
+            };
+            // One unreadable paste should not fail the whole search.
+            let matched = title_matches
+                || text_matches().unwrap_or_else(|e| {
+                    tracing::error!("failed to search paste {uuid}: {e}");
+                    false
+                });
+            if matched {
+                matches.push((uuid, meta));
+            }
+        }
+        Ok(matches)
+    })
+    .await?;
+    Ok(Json(paginate(matches, offset, limit)))
+}
+
+async fn healthz(State(state): State<SharedState>) -> impl IntoResponse {
+    if state.ready.load(Ordering::SeqCst) {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "shutting down")
+    }
+}
+
+async fn metrics_(State(state): State<SharedState>) -> Result<impl IntoResponse, AppError> {
+    let text = state
+        .metrics
+        .render(state.store.list()?.len(), state.store.total_bytes());
+    Ok(([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn test_reservations_count_towards_the_quota() {
+        let limits = Limits {
+            store_quota: 1000,
+            ..Limits::default()
+        };
+        let state = AppState::new(Box::<MemoryStorage>::default(), &limits);
+        let first = state.reserve(600).ok().unwrap();
+        assert!(matches!(state.reserve(600), Err(AppError::QuotaExceeded)));
+        assert!(state.reserve(400).is_ok());
+        drop(first);
+        assert!(state.reserve(600).is_ok());
+
+        let body = state.store.stage(&mut &[0; 600][..], None).unwrap();
+        let meta = Metadata::new(body.revision(DEFAULT_CONTENT_TYPE));
+        state.store.insert(Uuid::new_v4(), meta, body).unwrap();
+        assert!(matches!(state.reserve(600), Err(AppError::QuotaExceeded)));
+    }
+}
@@ -0,0 +1,108 @@
+use std::{
+    io::{self, Read, Write},
+    path::PathBuf,
+};
+
+use clap::{Parser, Subcommand};
+use pastebin::{api::StoreOptions, client::Client, paste::DEFAULT_CONTENT_TYPE};
+use uuid::Uuid;
+
+#[derive(Parser)]
+#[command(version, about = "A client for the pastebin server")]
+struct Args {
+    /// The server's base URL
+    #[arg(long, env = "PASTEBIN_URL", default_value = "http://127.0.0.1:10086")]
+    server: String,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Stores each file, or stdin if there are none, and prints their URLs
+    Store {
+        /// Encrypt the pastes so the server never sees them; the key is only kept in the URL.
+        /// Titles and content types would be sent in the clear, so they cannot be given.
+        #[arg(long, conflicts_with_all = ["title", "content_type"])]
+        encrypt: bool,
+        /// Delete the pastes after this many seconds
+        #[arg(long)]
+        ttl: Option<u64>,
+        /// Delete the pastes as soon as they have been loaded once
+        #[arg(long)]
+        burn: bool,
+        #[arg(long)]
+        title: Option<String>,
+        #[arg(long, default_value = DEFAULT_CONTENT_TYPE)]
+        content_type: String,
+        files: Vec<PathBuf>,
+    },
+    /// Prints a paste, given its UUID or its URL; URLs with a key are decrypted
+    Load {
+        paste: String,
+        /// The revision to load instead of the latest one
+        #[arg(long)]
+        rev: Option<u32>,
+    },
+    /// Deletes a paste
+    Delete {
+        uuid: Uuid,
+        /// The token printed when the paste was stored
+        #[arg(long, env = "PASTEBIN_TOKEN")]
+        token: String,
+    },
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    if let Err(e) = run(args).await {
+        eprintln!("error: {e}");
+        std::process::exit(1);
+    }
+}
+
+async fn run(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let client = Client::new(args.server);
+    match args.command {
+        Command::Store {
+            encrypt,
+            ttl,
+            burn,
+            title,
+            content_type,
+            files,
+        } => {
+            let options = StoreOptions { ttl, burn, title };
+            let bodies = if files.is_empty() {
+                let mut body = Vec::new();
+                io::stdin().read_to_end(&mut body)?;
+                vec![body]
+            } else {
+                files.iter().map(std::fs::read).collect::<io::Result<_>>()?
+            };
+            for body in bodies {
+                let (response, key) = if encrypt {
+                    let (response, key) = client.store_encrypted(&body, &options).await?;
+                    (response, Some(key))
+                } else {
+                    let response = client.store(body, &content_type, &options).await?;
+                    (response, None)
+                };
+                println!("{}", client.paste_url(&response.uuid, key.as_ref()));
+                eprintln!("delete token: {}", response.token);
+            }
+        }
+        Command::Load { paste, rev } => {
+            let body = match paste.parse::<Uuid>() {
+                Ok(uuid) => client.load(&uuid, rev).await?,
+                Err(_) if rev.is_some() => return Err("--rev needs a UUID, not a URL".into()),
+                Err(_) => client.load_url(&paste).await?,
+            };
+            io::stdout().write_all(&body)?;
+        }
+        Command::Delete { uuid, token } => client.delete(&uuid, &token).await?,
+    }
+    Ok(())
+}
@@ -0,0 +1,168 @@
+//! A client for the pastebin API, which can encrypt pastes before they leave the machine.
+
+use std::fmt;
+
+use reqwest::{header, StatusCode};
+use uuid::Uuid;
+
+use crate::{
+    api::{StoreOptions, StoreResponse, DELETE_TOKEN_HEADER, NONCE_HEADER},
+    crypto::Key,
+};
+
+/// The content type encrypted pastes are stored with; the real one is unknown to the server.
+const ENCRYPTED_CONTENT_TYPE: &str = "application/octet-stream";
+
+#[derive(Debug)]
+pub enum ClientError {
+    Http(reqwest::Error),
+    /// The server answered with an error status.
+    Status(StatusCode),
+    /// The URL is not the URL of a paste.
+    InvalidUrl(String),
+    /// The paste could not be decrypted with the key in the URL.
+    Decrypt,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Http(e) => write!(f, "request failed: {e}"),
+            ClientError::Status(status) => write!(f, "server responded with {status}"),
+            ClientError::InvalidUrl(url) => write!(f, "not a paste URL: {url}"),
+            ClientError::Decrypt => f.write_str("cannot decrypt the paste, is the key right?"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> Self {
+        ClientError::Http(e)
+    }
+}
+
+pub struct Client {
+    /// The server's base URL, without a trailing slash.
+    server: String,
+    http: reqwest::Client,
+}
+
+impl Client {
+    /// Creates a client for the server at `server`, e.g. `http://127.0.0.1:10086`.
+    pub fn new(server: impl Into<String>) -> Self {
+        let server = server.into();
+        Client {
+            server: server.trim_end_matches('/').to_string(),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// The URL a paste can be loaded from. For encrypted pastes, pass the key so that it ends up
+    /// in the fragment.
+    pub fn paste_url(&self, uuid: &Uuid, key: Option<&Key>) -> String {
+        match key {
+            Some(key) => format!("{}/load/{uuid}#{}", self.server, key.to_fragment()),
+            None => format!("{}/load/{uuid}", self.server),
+        }
+    }
+
+    pub async fn store(
+        &self,
+        body: impl Into<Vec<u8>>,
+        content_type: &str,
+        options: &StoreOptions,
+    ) -> Result<StoreResponse, ClientError> {
+        let response = self
+            .http
+            .post(format!("{}/store", self.server))
+            .query(options)
+            .header(header::CONTENT_TYPE, content_type)
+            .body(body.into())
+            .send()
+            .await?;
+        Ok(check(response)?.json().await?)
+    }
+
+    /// Encrypts `plaintext` under a fresh key and stores it. The key is returned and never sent
+    /// to the server.
+    pub async fn store_encrypted(
+        &self,
+        plaintext: &[u8],
+        options: &StoreOptions,
+    ) -> Result<(StoreResponse, Key), ClientError> {
+        let key = Key::generate();
+        let (nonce, ciphertext) = key.encrypt(plaintext);
+        let response = self
+            .http
+            .post(format!("{}/store", self.server))
+            .query(options)
+            .header(header::CONTENT_TYPE, ENCRYPTED_CONTENT_TYPE)
+            .header(NONCE_HEADER, nonce)
+            .body(ciphertext)
+            .send()
+            .await?;
+        Ok((check(response)?.json().await?, key))
+    }
+
+    /// Loads a revision of a paste, or its latest revision if `revision` is `None`.
+    pub async fn load(&self, uuid: &Uuid, revision: Option<u32>) -> Result<Vec<u8>, ClientError> {
+        let mut url = self.paste_url(uuid, None);
+        if let Some(revision) = revision {
+            url = format!("{url}?rev={revision}");
+        }
+        self.fetch(&url, None).await
+    }
+
+    /// Loads the paste behind `url`, decrypting it with the key in the fragment if there is one.
+    pub async fn load_url(&self, url: &str) -> Result<Vec<u8>, ClientError> {
+        let (url, key) = match url.split_once('#') {
+            Some((url, fragment)) => {
+                let key = Key::from_fragment(fragment)
+                    .ok_or_else(|| ClientError::InvalidUrl(url.to_string()))?;
+                (url, Some(key))
+            }
+            None => (url, None),
+        };
+        if !url.contains("/load/") {
+            return Err(ClientError::InvalidUrl(url.to_string()));
+        }
+        self.fetch(url, key).await
+    }
+
+    async fn fetch(&self, url: &str, key: Option<Key>) -> Result<Vec<u8>, ClientError> {
+        let response = check(self.http.get(url).send().await?)?;
+        let nonce = response
+            .headers()
+            .get(NONCE_HEADER)
+            .and_then(|nonce| nonce.to_str().ok())
+            .map(str::to_string);
+        let body = response.bytes().await?;
+        match (key, nonce) {
+            (Some(key), Some(nonce)) => key.decrypt(&nonce, &body).ok_or(ClientError::Decrypt),
+            // A key for a paste that was not encrypted, or the other way round.
+            (Some(_), None) | (None, Some(_)) => Err(ClientError::Decrypt),
+            (None, None) => Ok(body.into()),
+        }
+    }
+
+    /// Deletes a paste, given the token returned when it was stored.
+    pub async fn delete(&self, uuid: &Uuid, token: &str) -> Result<(), ClientError> {
+        let response = self
+            .http
+            .delete(format!("{}/delete/{uuid}", self.server))
+            .header(DELETE_TOKEN_HEADER, token)
+            .send()
+            .await?;
+        check(response)?;
+        Ok(())
+    }
+}
+
+fn check(response: reqwest::Response) -> Result<reqwest::Response, ClientError> {
+    match response.status() {
+        status if status.is_success() => Ok(response),
+        status => Err(ClientError::Status(status)),
+    }
+}
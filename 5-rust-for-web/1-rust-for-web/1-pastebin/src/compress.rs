@@ -0,0 +1,126 @@
+//! Gzip compression of stored bodies, and negotiating it with clients.
+
+use std::io::{self, Read, Write};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+
+use crate::paste::Encoding;
+
+/// How much of a body is compressed up front to judge whether compressing all of it is worth it.
+const SAMPLE_LEN: usize = 64 * 1024;
+
+/// Copies `body` into `sink`, gzipping it if it is larger than `threshold` bytes and that makes
+/// it smaller, as judged by its beginning. At most `threshold` bytes, or the sample if that is
+/// larger, are held in memory at a time.
+pub fn encode<W: Write>(
+    body: &mut dyn Read,
+    mut sink: W,
+    threshold: usize,
+) -> io::Result<(W, Encoding)> {
+    let mut head = Vec::new();
+    let head_len = threshold.max(SAMPLE_LEN).saturating_add(1);
+    (&mut *body).take(head_len as u64).read_to_end(&mut head)?;
+    if head.len() <= threshold {
+        sink.write_all(&head)?;
+        return Ok((sink, Encoding::Identity));
+    }
+    let sample = &head[..head.len().min(SAMPLE_LEN)];
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(sample)?;
+    if encoder.finish()?.len() >= sample.len() {
+        sink.write_all(&head)?;
+        io::copy(body, &mut sink)?;
+        return Ok((sink, Encoding::Identity));
+    }
+    let mut encoder = GzEncoder::new(sink, Compression::default());
+    encoder.write_all(&head)?;
+    io::copy(body, &mut encoder)?;
+    Ok((encoder.finish()?, Encoding::Gzip))
+}
+
+/// Undoes [`encode`] while `body` is read.
+pub fn decoder<'a>(body: impl Read + Send + 'a, encoding: Encoding) -> Box<dyn Read + Send + 'a> {
+    match encoding {
+        Encoding::Identity => Box::new(body),
+        Encoding::Gzip => Box::new(GzDecoder::new(body)),
+    }
+}
+
+/// Undoes [`encode`] all at once.
+pub fn decode(body: Vec<u8>, encoding: Encoding) -> io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Identity => Ok(body),
+        Encoding::Gzip => {
+            let mut decoded = Vec::new();
+            decoder(&body[..], encoding).read_to_end(&mut decoded)?;
+            Ok(decoded)
+        }
+    }
+}
+
+/// Whether an `Accept-Encoding` header allows a gzipped response.
+pub fn accepts_gzip(accept_encoding: &str) -> bool {
+    let mut wildcard = None;
+    for coding in accept_encoding.split(',') {
+        let mut params = coding.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let quality = params
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .map_or(1.0, |q| q.trim().parse().unwrap_or(0.0));
+        if name.eq_ignore_ascii_case("gzip") || name.eq_ignore_ascii_case("x-gzip") {
+            return quality > 0.0;
+        }
+        if name == "*" {
+            wildcard = Some(quality > 0.0);
+        }
+    }
+    wildcard.unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let encode =
+            |body: &[u8], threshold| encode(&mut &body[..], Vec::new(), threshold).unwrap();
+        let log = "INFO request handled in 3ms\n".repeat(100).into_bytes();
+        let (small, encoding) = encode(&log, log.len());
+        assert_eq!((&small, encoding), (&log, Encoding::Identity));
+
+        let (compressed, encoding) = encode(&log, 16);
+        assert_eq!(encoding, Encoding::Gzip);
+        assert!(compressed.len() < log.len() / 10);
+        let mut decoded = Vec::new();
+        decoder(&compressed[..], encoding)
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, log);
+        assert_eq!(decode(compressed, encoding).unwrap(), log);
+
+        // Not worth it for data that does not compress.
+        let (random, encoding) = encode(uuid::Uuid::new_v4().as_bytes(), 0);
+        assert_eq!((random.len(), encoding), (16, Encoding::Identity));
+    }
+
+    #[test]
+    fn test_encode_beyond_sample() {
+        // Larger than the sample, so some of it is streamed straight into the encoder.
+        let log = "DEBUG polling queue\n".repeat(10_000).into_bytes();
+        let (compressed, encoding) = encode(&mut &log[..], Vec::new(), 0).unwrap();
+        assert_eq!(encoding, Encoding::Gzip);
+        assert_eq!(decode(compressed, encoding).unwrap(), log);
+    }
+
+    #[test]
+    fn test_accepts_gzip() {
+        assert!(accepts_gzip("gzip"));
+        assert!(accepts_gzip("deflate, gzip;q=0.5, br"));
+        assert!(accepts_gzip("*"));
+        assert!(!accepts_gzip(""));
+        assert!(!accepts_gzip("br, deflate"));
+        assert!(!accepts_gzip("gzip;q=0"));
+        assert!(!accepts_gzip("gzip;q=0, *"));
+    }
+}
@@ -0,0 +1,307 @@
+//! Server configuration, gathered from command-line flags, environment variables and an
+//! optional TOML file, in that order of precedence.
+
+use std::{fmt, fs, io, net::SocketAddr, path::PathBuf, time::Duration};
+
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
+use tracing::Level;
+
+use crate::storage::{DiskStorage, MemoryStorage, Storage};
+
+/// Every setting is optional here, so that flags, environment variables and the config file
+/// can each fill in the gaps left by the others.
+#[derive(Debug, Default, Parser, Deserialize)]
+#[command(version, about = "A small pastebin server")]
+#[serde(deny_unknown_fields)]
+pub struct Options {
+    /// Path to a TOML file with any of the options below, using their long names with `_`
+    #[arg(long, env = "PASTEBIN_CONFIG")]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+
+    /// The address to listen on [default: 0.0.0.0:10086]
+    #[arg(long, env = "PASTEBIN_LISTEN")]
+    pub listen: Option<SocketAddr>,
+
+    /// One of trace, debug, info, warn or error [default: debug]
+    #[arg(long, env = "PASTEBIN_LOG_LEVEL")]
+    pub log_level: Option<String>,
+
+    /// The format of log lines [default: text]
+    #[arg(long, env = "PASTEBIN_LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+
+    /// Where pastes are kept [default: disk]
+    #[arg(long, env = "PASTEBIN_STORAGE")]
+    pub storage: Option<StorageKind>,
+
+    /// The directory used by disk storage [default: pastes]
+    #[arg(long, env = "PASTEBIN_DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+
+    /// The largest paste accepted, in bytes [default: 1 MiB]
+    #[arg(long, env = "PASTEBIN_MAX_PASTE_SIZE")]
+    pub max_paste_size: Option<usize>,
+
+    /// The most bytes all pastes together may take up [default: 1 GiB]
+    #[arg(long, env = "PASTEBIN_STORE_QUOTA")]
+    pub store_quota: Option<u64>,
+
+    /// How many requests a client IP may make in a burst [default: 20]
+    #[arg(long, env = "PASTEBIN_RATE_LIMIT_BURST")]
+    pub rate_limit_burst: Option<u32>,
+
+    /// How many milliseconds it takes a client IP to earn another request [default: 500]
+    #[arg(long, env = "PASTEBIN_RATE_LIMIT_PERIOD_MS")]
+    pub rate_limit_period_ms: Option<u64>,
+
+    /// Bodies larger than this many bytes are stored gzipped [default: 16 KiB]
+    #[arg(long, env = "PASTEBIN_COMPRESS_ABOVE")]
+    pub compress_above: Option<usize>,
+
+    /// How many milliseconds to keep serving after a shutdown signal, with /healthz failing so
+    /// that load balancers stop sending requests here [default: 5000]
+    #[arg(long, env = "PASTEBIN_DRAIN_PERIOD_MS")]
+    pub drain_period_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    Memory,
+    Disk,
+}
+
+/// The validated configuration the server runs with.
+#[derive(Debug)]
+pub struct Config {
+    pub listen: SocketAddr,
+    pub log_level: Level,
+    pub log_format: LogFormat,
+    pub storage: StorageConfig,
+    pub limits: Limits,
+    /// How long to keep serving after a shutdown signal, before no longer accepting
+    /// connections.
+    pub drain_period: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StorageConfig {
+    Memory,
+    Disk { dir: PathBuf },
+}
+
+/// How much clients may store, how often they may ask, and how it is stored.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// The largest body accepted by `/store`, in bytes.
+    pub max_paste_size: usize,
+    /// The most bytes all pastes together may take up.
+    pub store_quota: u64,
+    /// How many requests a client IP may make in a burst.
+    pub rate_limit_burst: u32,
+    /// How long it takes for a client IP to earn another request.
+    pub rate_limit_period: Duration,
+    /// Bodies larger than this many bytes are stored gzipped.
+    pub compress_above: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_paste_size: 1024 * 1024,
+            store_quota: 1024 * 1024 * 1024,
+            rate_limit_burst: 20,
+            rate_limit_period: Duration::from_millis(500),
+            compress_above: 16 * 1024,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "cannot read {}: {e}", path.display()),
+            ConfigError::Parse(path, e) => write!(f, "invalid config file {}: {e}", path.display()),
+            ConfigError::Invalid(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Reads the configuration from the command line, the environment and the config file.
+    pub fn load() -> Result<Config, ConfigError> {
+        Config::from_options(Options::parse())
+    }
+
+    pub fn from_options(options: Options) -> Result<Config, ConfigError> {
+        let file = match &options.config {
+            Some(path) => {
+                let text =
+                    fs::read_to_string(path).map_err(|e| ConfigError::Read(path.clone(), e))?;
+                toml::from_str(&text).map_err(|e| ConfigError::Parse(path.clone(), e))?
+            }
+            None => Options::default(),
+        };
+        options.or(file).validate()
+    }
+}
+
+impl Options {
+    /// Fills in every option not set in `self` from `other`.
+    fn or(self, other: Options) -> Options {
+        Options {
+            config: self.config.or(other.config),
+            listen: self.listen.or(other.listen),
+            log_level: self.log_level.or(other.log_level),
+            log_format: self.log_format.or(other.log_format),
+            storage: self.storage.or(other.storage),
+            data_dir: self.data_dir.or(other.data_dir),
+            max_paste_size: self.max_paste_size.or(other.max_paste_size),
+            store_quota: self.store_quota.or(other.store_quota),
+            rate_limit_burst: self.rate_limit_burst.or(other.rate_limit_burst),
+            rate_limit_period_ms: self.rate_limit_period_ms.or(other.rate_limit_period_ms),
+            compress_above: self.compress_above.or(other.compress_above),
+            drain_period_ms: self.drain_period_ms.or(other.drain_period_ms),
+        }
+    }
+
+    fn validate(self) -> Result<Config, ConfigError> {
+        let invalid = |msg: String| Err(ConfigError::Invalid(msg));
+
+        let log_level = match self.log_level {
+            Some(level) => match level.parse() {
+                Ok(level) => level,
+                Err(_) => return invalid(format!("unknown log level `{level}`")),
+            },
+            None => Level::DEBUG,
+        };
+
+        let storage = match (self.storage.unwrap_or(StorageKind::Disk), self.data_dir) {
+            (StorageKind::Memory, Some(_)) => {
+                return invalid("`data_dir` cannot be used with memory storage".to_string())
+            }
+            (StorageKind::Memory, None) => StorageConfig::Memory,
+            (StorageKind::Disk, dir) => StorageConfig::Disk {
+                dir: dir.unwrap_or_else(|| "pastes".into()),
+            },
+        };
+
+        let defaults = Limits::default();
+        let limits = Limits {
+            max_paste_size: self.max_paste_size.unwrap_or(defaults.max_paste_size),
+            store_quota: self.store_quota.unwrap_or(defaults.store_quota),
+            rate_limit_burst: self.rate_limit_burst.unwrap_or(defaults.rate_limit_burst),
+            rate_limit_period: self
+                .rate_limit_period_ms
+                .map_or(defaults.rate_limit_period, Duration::from_millis),
+            compress_above: self.compress_above.unwrap_or(defaults.compress_above),
+        };
+        if limits.max_paste_size == 0 {
+            return invalid("`max_paste_size` must be at least 1".to_string());
+        }
+        if limits.store_quota < limits.max_paste_size as u64 {
+            return invalid(format!(
+                "`store_quota` ({}) is smaller than `max_paste_size` ({})",
+                limits.store_quota, limits.max_paste_size
+            ));
+        }
+        if limits.rate_limit_burst == 0 || limits.rate_limit_period.is_zero() {
+            return invalid(
+                "`rate_limit_burst` and `rate_limit_period_ms` must be at least 1".to_string(),
+            );
+        }
+
+        Ok(Config {
+            listen: self
+                .listen
+                .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 10086))),
+            log_level,
+            log_format: self.log_format.unwrap_or(LogFormat::Text),
+            storage,
+            limits,
+            drain_period: Duration::from_millis(self.drain_period_ms.unwrap_or(5000)),
+        })
+    }
+}
+
+impl StorageConfig {
+    pub fn open(&self) -> io::Result<Box<dyn Storage>> {
+        Ok(match self {
+            StorageConfig::Memory => Box::new(MemoryStorage::default()),
+            StorageConfig::Disk { dir } => Box::new(DiskStorage::open(dir)?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_defaults() {
+        let config = Config::from_options(Options::default()).unwrap();
+        assert_eq!(config.listen, "0.0.0.0:10086".parse().unwrap());
+        assert_eq!(config.log_level, Level::DEBUG);
+        assert_eq!(
+            config.storage,
+            StorageConfig::Disk {
+                dir: "pastes".into()
+            }
+        );
+        assert_eq!(config.drain_period, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_flags_override_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pastebin.toml");
+        fs::write(
+            &path,
+            "listen = \"127.0.0.1:8080\"\nlog_level = \"info\"\nstorage = \"memory\"\n",
+        )
+        .unwrap();
+
+        let options = Options::parse_from([
+            "pastebin",
+            "--config",
+            path.to_str().unwrap(),
+            "--log-level",
+            "warn",
+        ]);
+        let config = Config::from_options(options).unwrap();
+        assert_eq!(config.listen, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(config.log_level, Level::WARN);
+        assert_eq!(config.storage, StorageConfig::Memory);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let invalid = |args: &[&str]| {
+            let options = Options::parse_from(["pastebin"].iter().chain(args));
+            matches!(Config::from_options(options), Err(ConfigError::Invalid(_)))
+        };
+        assert!(invalid(&["--log-level", "loud"]));
+        assert!(invalid(&["--storage", "memory", "--data-dir", "pastes"]));
+        assert!(invalid(&["--max-paste-size", "0"]));
+        assert!(invalid(&["--max-paste-size", "100", "--store-quota", "10"]));
+        assert!(invalid(&["--rate-limit-burst", "0"]));
+    }
+}
@@ -0,0 +1,86 @@
+//! End-to-end encryption of pastes, done entirely by the client.
+//!
+//! The server only ever sees the ciphertext and the nonce. The key travels in the fragment of
+//! the paste's URL, which browsers and HTTP clients never send to the server.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Nonce,
+};
+
+/// The length of an encoded nonce: 12 bytes in unpadded URL-safe base64.
+pub const NONCE_LEN: usize = 16;
+
+/// A secret key that encrypts one paste, including all of its revisions.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Key(chacha20poly1305::Key);
+
+impl Key {
+    pub fn generate() -> Self {
+        Key(ChaCha20Poly1305::generate_key(&mut OsRng))
+    }
+
+    /// Decodes a key from a URL fragment, as produced by [`Key::to_fragment`].
+    pub fn from_fragment(fragment: &str) -> Option<Self> {
+        let bytes = URL_SAFE_NO_PAD.decode(fragment).ok()?;
+        (bytes.len() == 32).then(|| Key(*chacha20poly1305::Key::from_slice(&bytes)))
+    }
+
+    pub fn to_fragment(&self) -> String {
+        URL_SAFE_NO_PAD.encode(self.0)
+    }
+
+    /// Encrypts `plaintext` under a fresh random nonce, returning the encoded nonce and the
+    /// ciphertext.
+    pub fn encrypt(&self, plaintext: &[u8]) -> (String, Vec<u8>) {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = ChaCha20Poly1305::new(&self.0)
+            .encrypt(&nonce, plaintext)
+            .expect("a paste is far too small to overflow the cipher");
+        (URL_SAFE_NO_PAD.encode(nonce), ciphertext)
+    }
+
+    /// Decrypts and authenticates `ciphertext`, or returns `None` if the key or nonce is wrong
+    /// or the ciphertext has been tampered with.
+    pub fn decrypt(&self, nonce: &str, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        let nonce = URL_SAFE_NO_PAD.decode(nonce).ok()?;
+        if nonce.len() != 12 {
+            return None;
+        }
+        ChaCha20Poly1305::new(&self.0)
+            .decrypt(Nonce::from_slice(&nonce), ciphertext)
+            .ok()
+    }
+}
+
+/// Whether `nonce` looks like a nonce produced by [`Key::encrypt`]. The server checks no more
+/// than this, since it cannot decrypt anything anyway.
+pub fn is_valid_nonce(nonce: &str) -> bool {
+    nonce.len() == NONCE_LEN
+        && nonce
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let key = Key::generate();
+        let (nonce, ciphertext) = key.encrypt(b"secret");
+        assert!(is_valid_nonce(&nonce));
+        assert_ne!(ciphertext, b"secret");
+        assert_eq!(key.decrypt(&nonce, &ciphertext).unwrap(), b"secret");
+
+        let key = Key::from_fragment(&key.to_fragment()).unwrap();
+        assert_eq!(key.decrypt(&nonce, &ciphertext).unwrap(), b"secret");
+        assert_eq!(Key::generate().decrypt(&nonce, &ciphertext), None);
+
+        let mut tampered = ciphertext;
+        tampered[0] ^= 1;
+        assert_eq!(key.decrypt(&nonce, &tampered), None);
+    }
+}
@@ -0,0 +1,13 @@
+pub mod api;
+pub mod app;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod compress;
+pub mod config;
+pub mod crypto;
+pub mod metrics;
+pub mod paste;
+pub mod range;
+pub mod rate_limit;
+pub mod render;
+pub mod storage;
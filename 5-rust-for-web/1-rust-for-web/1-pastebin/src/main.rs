@@ -1,67 +1,91 @@
-use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
-};
+use std::{net::SocketAddr, sync::atomic::Ordering, time::Duration};
 
-use axum::{
-    extract::{Path, State},
-    http::StatusCode,
-    response::IntoResponse,
-    routing::{delete, get, post},
-    Router,
+use pastebin::{
+    app::{self, AppState, SharedState},
+    config::{Config, LogFormat},
 };
-use tower_http::trace::TraceLayer;
-use uuid::Uuid;
-
-struct AppState {
-    store: HashMap<Uuid, String>,
-}
 
 #[tokio::main]
 async fn main() {
-    let state = Arc::new(Mutex::new(AppState {
-        store: HashMap::new(),
-    }));
-    let app = Router::new()
-        .route("/store", post(store))
-        .route("/load/:uuid", get(load))
-        .route("/delete/:uuid", delete(delete_))
-        .with_state(state)
-        .layer(TraceLayer::new_for_http());
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {e}");
+            std::process::exit(2);
+        }
+    };
 
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::DEBUG)
-        .init();
+    let subscriber = tracing_subscriber::fmt().with_max_level(config.log_level);
+    match config.log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
 
-    let listner = tokio::net::TcpListener::bind("0.0.0.0:10086")
-        .await
-        .unwrap();
+    let storage = match config.storage.open() {
+        Ok(storage) => storage,
+        Err(e) => {
+            tracing::error!("cannot open storage: {e}");
+            std::process::exit(1);
+        }
+    };
 
-    axum::serve(listner, app).await.unwrap();
-}
+    let state = AppState::new(storage, &config.limits);
+    let app = app::router(state.clone(), &config.limits);
 
-async fn store(State(state): State<Arc<Mutex<AppState>>>, body: String) -> impl IntoResponse {
-    let uuid = Uuid::new_v4();
-    state.lock().unwrap().store.insert(uuid, body);
-    uuid.hyphenated().to_string()
-}
+    tokio::spawn(app::purge_expired(state.clone()));
+
+    let listener = match tokio::net::TcpListener::bind(config.listen).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            tracing::error!("cannot listen on {}: {e}", config.listen);
+            std::process::exit(1);
+        }
+    };
+    tracing::info!("listening on {}", config.listen);
 
-async fn load(
-    State(state): State<Arc<Mutex<AppState>>>,
-    Path(uuid): Path<Uuid>,
-) -> impl IntoResponse {
-    match state.lock().unwrap().store.get(&uuid) {
-        Some(text) => text.clone().into_response(),
-        None => StatusCode::NOT_FOUND.into_response(),
+    let served = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal(state.clone(), config.drain_period))
+    .await;
+    if let Err(e) = served {
+        tracing::error!("server error: {e}");
+    }
+
+    match state.store.flush() {
+        Ok(()) => tracing::info!("storage flushed, bye"),
+        Err(e) => tracing::error!("failed to flush storage: {e}"),
     }
 }
 
-async fn delete_(
-    State(state): State<Arc<Mutex<AppState>>>,
-    Path(uuid): Path<Uuid>,
-) -> impl IntoResponse {
-    match state.lock().unwrap().store.remove(&uuid) {
-        Some(_) => ().into_response(),
-        None => StatusCode::NOT_FOUND.into_response(),
+/// Waits for ctrl-c or SIGTERM, then marks the server as no longer ready and keeps serving
+/// for `drain_period`, so load balancers see `/healthz` fail and stop sending requests here.
+///
+/// Once this returns, the server stops accepting connections and waits for in-flight
+/// requests to finish.
+async fn shutdown_signal(state: SharedState, drain_period: Duration) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for ctrl-c");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
     }
+    state.ready.store(false, Ordering::SeqCst);
+    tracing::info!("shutting down in {drain_period:?}, until then /healthz fails");
+    tokio::time::sleep(drain_period).await;
+    tracing::info!("no longer accepting connections, draining in-flight requests");
 }
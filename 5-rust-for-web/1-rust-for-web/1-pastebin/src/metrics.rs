@@ -0,0 +1,204 @@
+//! Request metrics, collected by a tower layer and exposed in the Prometheus text format.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{MatchedPath, Request},
+    response::Response,
+};
+use tower::{Layer, Service};
+
+/// Upper bounds of the request latency histogram buckets, in seconds.
+const BUCKETS: [f64; 8] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+#[derive(Default)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Keyed by method, route and status code.
+    requests: BTreeMap<(String, String, u16), u64>,
+    /// Keyed by route.
+    latencies: BTreeMap<String, Histogram>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// How many observations fell into each bucket; not cumulative.
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Metrics {
+    fn record(&self, method: &str, route: &str, status: u16, latency: Duration) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        *inner
+            .requests
+            .entry((method.to_string(), route.to_string(), status))
+            .or_default() += 1;
+
+        let histogram = inner.latencies.entry(route.to_string()).or_default();
+        let secs = latency.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|&le| secs <= le) {
+            histogram.buckets[i] += 1;
+        }
+        histogram.sum += secs;
+        histogram.count += 1;
+    }
+
+    /// Renders all metrics, plus the given store statistics, in the Prometheus text format.
+    pub fn render(&self, store_pastes: usize, store_bytes: u64) -> String {
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let mut out = String::new();
+
+        out.push_str("# HELP pastebin_requests_total Number of HTTP requests handled.\n");
+        out.push_str("# TYPE pastebin_requests_total counter\n");
+        for ((method, route, status), count) in &inner.requests {
+            let _ = writeln!(
+                out,
+                "pastebin_requests_total{{method=\"{method}\",route=\"{route}\",status=\"{status}\"}} {count}"
+            );
+        }
+
+        out.push_str(
+            "# HELP pastebin_request_duration_seconds Time taken to handle HTTP requests.\n",
+        );
+        out.push_str("# TYPE pastebin_request_duration_seconds histogram\n");
+        for (route, histogram) in &inner.latencies {
+            let mut cumulative = 0;
+            for (le, count) in BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "pastebin_request_duration_seconds_bucket{{route=\"{route}\",le=\"{le}\"}} {cumulative}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "pastebin_request_duration_seconds_bucket{{route=\"{route}\",le=\"+Inf\"}} {}",
+                histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "pastebin_request_duration_seconds_sum{{route=\"{route}\"}} {}",
+                histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "pastebin_request_duration_seconds_count{{route=\"{route}\"}} {}",
+                histogram.count
+            );
+        }
+
+        out.push_str("# HELP pastebin_store_pastes Number of stored pastes.\n");
+        out.push_str("# TYPE pastebin_store_pastes gauge\n");
+        let _ = writeln!(out, "pastebin_store_pastes {store_pastes}");
+        out.push_str("# HELP pastebin_store_bytes Combined size of all stored bodies, duplicates counted once.\n");
+        out.push_str("# TYPE pastebin_store_bytes gauge\n");
+        let _ = writeln!(out, "pastebin_store_bytes {store_bytes}");
+        out
+    }
+}
+
+/// Records the count and latency of every request into [`Metrics`].
+#[derive(Clone)]
+pub struct MetricsLayer {
+    metrics: Arc<Metrics>,
+}
+
+impl MetricsLayer {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        MetricsLayer { metrics }
+    }
+}
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = RecordMetrics<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RecordMetrics {
+            inner,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+/// The service created by [`MetricsLayer`].
+#[derive(Clone)]
+pub struct RecordMetrics<S> {
+    inner: S,
+    metrics: Arc<Metrics>,
+}
+
+impl<S> Service<Request> for RecordMetrics<S>
+where
+    S: Service<Request, Response = Response>,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let start = Instant::now();
+        let method = req.method().to_string();
+        // Label by route rather than by path, so that every paste does not get its own series.
+        let route = req
+            .extensions()
+            .get::<MatchedPath>()
+            .map_or("<unmatched>", |path| path.as_str())
+            .to_string();
+        let metrics = self.metrics.clone();
+        let future = self.inner.call(req);
+        Box::pin(async move {
+            let response = future.await?;
+            metrics.record(&method, &route, response.status().as_u16(), start.elapsed());
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.record("GET", "/load/:uuid", 200, Duration::from_millis(3));
+        metrics.record("GET", "/load/:uuid", 404, Duration::from_secs(10));
+
+        let text = metrics.render(2, 42);
+        for line in [
+            "pastebin_requests_total{method=\"GET\",route=\"/load/:uuid\",status=\"200\"} 1",
+            "pastebin_requests_total{method=\"GET\",route=\"/load/:uuid\",status=\"404\"} 1",
+            "pastebin_request_duration_seconds_bucket{route=\"/load/:uuid\",le=\"0.001\"} 0",
+            "pastebin_request_duration_seconds_bucket{route=\"/load/:uuid\",le=\"0.005\"} 1",
+            "pastebin_request_duration_seconds_bucket{route=\"/load/:uuid\",le=\"5\"} 1",
+            "pastebin_request_duration_seconds_bucket{route=\"/load/:uuid\",le=\"+Inf\"} 2",
+            "pastebin_request_duration_seconds_count{route=\"/load/:uuid\"} 2",
+            "pastebin_store_pastes 2",
+            "pastebin_store_bytes 42",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing `{line}` in:\n{text}"
+            );
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use std::{
+    io::{self, Read, Seek},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::compress;
+
+/// The content type of pastes stored without one.
+pub const DEFAULT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+
+/// One revision of a stored paste: its body and everything the server knows about it.
+///
+/// The body is either read already or, as a [`BodyReader`], yet to be read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Paste<B = Vec<u8>> {
+    pub meta: Metadata,
+    /// Which of `meta.revisions` the body belongs to.
+    pub revision: u32,
+    pub body: B,
+    /// How `body` is encoded; see [`Paste::decode`].
+    pub encoding: Encoding,
+}
+
+/// A stored body being read, as it is stored.
+pub trait BodyReader: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> BodyReader for T {}
+
+/// How a body is stored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Identity,
+    Gzip,
+}
+
+/// Everything about a paste except its bodies.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    /// A human-readable title chosen by the creator.
+    pub title: Option<String>,
+    pub created_at: SystemTime,
+    /// The paste can no longer be loaded after this point in time.
+    pub expires_at: Option<SystemTime>,
+    /// The paste is deleted as soon as it has been loaded once.
+    pub burn_after_read: bool,
+    /// The secret handed to the creator, required to update or delete the paste.
+    pub delete_token: String,
+    /// Every revision of the paste, oldest first. Never empty.
+    pub revisions: Vec<Revision>,
+}
+
+/// Everything about one revision of a paste except its body.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Revision {
+    /// The `Content-Type` the revision was stored with, sent back when it is loaded.
+    pub content_type: String,
+    /// The length of the body in bytes.
+    pub size: u64,
+    /// The SHA-256 of the body in hex. Bodies are stored under their hash, so identical
+    /// bodies are only stored once.
+    pub hash: String,
+    pub created_at: SystemTime,
+    /// Set if the body was encrypted by the client; the server cannot read it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+}
+
+impl Revision {
+    pub fn new(body: &[u8], content_type: impl Into<String>) -> Self {
+        Revision {
+            content_type: content_type.into(),
+            size: body.len() as u64,
+            hash: content_hash(body),
+            created_at: SystemTime::now(),
+            nonce: None,
+        }
+    }
+}
+
+/// The hash bodies are stored under.
+pub fn content_hash(body: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(body))
+}
+
+impl<B> Paste<B> {
+    /// The revision the body belongs to.
+    pub fn current(&self) -> &Revision {
+        &self.meta.revisions[self.revision as usize]
+    }
+}
+
+impl Paste<Box<dyn BodyReader>> {
+    /// Reads the whole body into memory.
+    pub fn read(mut self) -> io::Result<Paste> {
+        let mut body = Vec::new();
+        self.body.read_to_end(&mut body)?;
+        Ok(Paste {
+            meta: self.meta,
+            revision: self.revision,
+            body,
+            encoding: self.encoding,
+        })
+    }
+}
+
+impl Paste {
+    /// Creates a new paste with `body` as its only revision.
+    pub fn new(body: impl Into<Vec<u8>>, content_type: impl Into<String>) -> Self {
+        let body = body.into();
+        Paste {
+            meta: Metadata::new(Revision::new(&body, content_type)),
+            revision: 0,
+            body,
+            encoding: Encoding::Identity,
+        }
+    }
+
+    /// Decompresses the body, if it was stored compressed.
+    pub fn decode(mut self) -> io::Result<Self> {
+        self.body = compress::decode(self.body, self.encoding)?;
+        self.encoding = Encoding::Identity;
+        Ok(self)
+    }
+
+    /// The body as text, if the paste is text at all and not encrypted. Call
+    /// [`Paste::decode`] first.
+    pub fn text(&self) -> Option<&str> {
+        if self.current().nonce.is_some() || self.encoding != Encoding::Identity {
+            return None;
+        }
+        std::str::from_utf8(&self.body).ok()
+    }
+}
+
+impl Metadata {
+    /// The metadata of a new paste with `revision` as its only revision.
+    pub fn new(revision: Revision) -> Self {
+        Metadata {
+            title: None,
+            created_at: revision.created_at,
+            expires_at: None,
+            burn_after_read: false,
+            delete_token: Uuid::new_v4().simple().to_string(),
+            revisions: vec![revision],
+        }
+    }
+
+    /// The number of the newest revision.
+    pub fn latest(&self) -> u32 {
+        self.revisions.len() as u32 - 1
+    }
+
+    pub fn latest_revision(&self) -> &Revision {
+        &self.revisions[self.revisions.len() - 1]
+    }
+
+    /// The combined size of all revisions in bytes.
+    pub fn size(&self) -> u64 {
+        self.revisions.iter().map(|revision| revision.size).sum()
+    }
+
+    /// Whether the paste may show up in listings and search results.
+    pub fn is_listed(&self, now: SystemTime) -> bool {
+        !self.is_expired(now) && !self.burn_after_read
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|t| t <= now)
+    }
+
+    /// Checks `token` against the delete token without leaking how much of it matched.
+    pub fn can_delete(&self, token: &str) -> bool {
+        let expected = self.delete_token.as_bytes();
+        let token = token.as_bytes();
+        expected.len() == token.len()
+            && expected
+                .iter()
+                .zip(token)
+                .fold(0, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_can_delete() {
+        let meta = Paste::new("text", DEFAULT_CONTENT_TYPE).meta;
+        assert!(meta.can_delete(&meta.delete_token.clone()));
+        assert!(!meta.can_delete(""));
+        assert!(!meta.can_delete(&meta.delete_token[1..]));
+        assert!(!meta.can_delete(&Uuid::new_v4().simple().to_string()));
+    }
+}
@@ -0,0 +1,71 @@
+//! Parsing of HTTP `Range` headers, for downloading part of a paste.
+
+use std::ops::Range;
+
+/// The requested range lies entirely beyond the end of the body.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Unsatisfiable;
+
+/// Parses a `Range` header against a body of `len` bytes, returning the byte range to send.
+///
+/// Headers that are malformed, use another unit or ask for several ranges give `Ok(None)`: the
+/// header is then ignored and the whole body sent, as HTTP allows.
+pub fn parse(header: &str, len: u64) -> Result<Option<Range<u64>>, Unsatisfiable> {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((start, end)) = spec.split_once('-') else {
+        return Ok(None);
+    };
+    let (start, end) = (start.trim(), end.trim());
+    if start.is_empty() {
+        // The last `end` bytes.
+        let Ok(suffix) = end.parse::<u64>() else {
+            return Ok(None);
+        };
+        if suffix == 0 || len == 0 {
+            return Err(Unsatisfiable);
+        }
+        return Ok(Some(len.saturating_sub(suffix)..len));
+    }
+    let Ok(start) = start.parse::<u64>() else {
+        return Ok(None);
+    };
+    let end = match end {
+        "" => len,
+        end => match end.parse::<u64>() {
+            Ok(last) if last >= start => last.saturating_add(1).min(len),
+            _ => return Ok(None),
+        },
+    };
+    if start >= len {
+        return Err(Unsatisfiable);
+    }
+    Ok(Some(start..end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("bytes=0-4", 10), Ok(Some(0..5)));
+        assert_eq!(parse("bytes=5-", 10), Ok(Some(5..10)));
+        assert_eq!(parse("bytes=-3", 10), Ok(Some(7..10)));
+        assert_eq!(parse("bytes=-30", 10), Ok(Some(0..10)));
+        assert_eq!(parse("bytes=8-100", 10), Ok(Some(8..10)));
+
+        assert_eq!(parse("bytes=10-", 10), Err(Unsatisfiable));
+        assert_eq!(parse("bytes=-0", 10), Err(Unsatisfiable));
+        assert_eq!(parse("bytes=0-", 0), Err(Unsatisfiable));
+
+        assert_eq!(parse("bytes=0-1, 4-5", 10), Ok(None));
+        assert_eq!(parse("bytes=5-4", 10), Ok(None));
+        assert_eq!(parse("lines=1-2", 10), Ok(None));
+        assert_eq!(parse("bytes=a-b", 10), Ok(None));
+    }
+}
@@ -0,0 +1,142 @@
+//! A tower layer that limits how many requests each client IP may make.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use tower::{Layer, Service};
+
+/// Allows each client IP `burst` requests at once, refilled at one request per `period`.
+///
+/// Requests from clients without a known address (i.e. when the app is not served with
+/// [`ConnectInfo`]) are not limited.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limiter: Arc<Limiter>,
+}
+
+impl RateLimitLayer {
+    pub fn new(burst: u32, period: Duration) -> Self {
+        RateLimitLayer {
+            limiter: Arc::new(Limiter {
+                period,
+                tolerance: period * burst.saturating_sub(1),
+                clients: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+/// The service created by [`RateLimitLayer`].
+#[derive(Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    limiter: Arc<Limiter>,
+}
+
+impl<S> Service<Request> for RateLimit<S>
+where
+    S: Service<Request, Response = Response>,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let ip = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        if let Some(ip) = ip {
+            if let Err(retry_after) = self.limiter.check(ip, Instant::now()) {
+                let retry_after = retry_after.as_secs_f64().ceil() as u64;
+                let response = (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(header::RETRY_AFTER, retry_after.to_string())],
+                )
+                    .into_response();
+                return Box::pin(async { Ok(response) });
+            }
+        }
+        Box::pin(self.inner.call(req))
+    }
+}
+
+/// A generic cell rate algorithm: every client has a theoretical arrival time that moves
+/// `period` into the future with each request, and may run at most `tolerance` ahead of now.
+struct Limiter {
+    period: Duration,
+    tolerance: Duration,
+    clients: Mutex<HashMap<IpAddr, Instant>>,
+}
+
+/// Beyond this many tracked clients, clients that are back to a full burst are forgotten.
+const MAX_IDLE_CLIENTS: usize = 1024;
+
+impl Limiter {
+    /// Admits a request from `ip`, or returns how long it has to wait.
+    fn check(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+        if clients.len() > MAX_IDLE_CLIENTS {
+            clients.retain(|_, tat| *tat > now);
+        }
+        let tat = clients.get(&ip).map_or(now, |tat| (*tat).max(now));
+        let ahead = tat - now;
+        if ahead > self.tolerance {
+            return Err(ahead - self.tolerance);
+        }
+        clients.insert(ip, tat + self.period);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_burst_then_refill() {
+        let limiter = RateLimitLayer::new(3, Duration::from_secs(10)).limiter;
+        let (a, b) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(limiter.check(a, now), Ok(()));
+        }
+        assert_eq!(limiter.check(a, now), Err(Duration::from_secs(10)));
+        assert_eq!(limiter.check(b, now), Ok(()));
+
+        let later = now + Duration::from_secs(4);
+        assert_eq!(limiter.check(a, later), Err(Duration::from_secs(6)));
+        let later = now + Duration::from_secs(10);
+        assert_eq!(limiter.check(a, later), Ok(()));
+        assert!(limiter.check(a, later).is_err());
+    }
+}
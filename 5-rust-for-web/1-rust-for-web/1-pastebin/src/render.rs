@@ -0,0 +1,199 @@
+//! Renders text pastes as HTML with line numbers and simple syntax highlighting.
+
+use std::fmt::Write;
+
+struct Language {
+    names: &'static [&'static str],
+    keywords: &'static [&'static str],
+    line_comment: &'static str,
+    quotes: &'static [char],
+}
+
+const LANGUAGES: &[Language] = &[
+    Language {
+        names: &["rust", "rs"],
+        keywords: &[
+            "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum",
+            "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod",
+            "move", "mut", "pub", "ref", "return", "self", "Self", "static", "struct", "super",
+            "trait", "true", "type", "unsafe", "use", "where", "while",
+        ],
+        line_comment: "//",
+        quotes: &['"'],
+    },
+    Language {
+        names: &["python", "py"],
+        keywords: &[
+            "and", "as", "assert", "async", "await", "break", "class", "continue", "def", "del",
+            "elif", "else", "except", "False", "finally", "for", "from", "global", "if", "import",
+            "in", "is", "lambda", "None", "nonlocal", "not", "or", "pass", "raise", "return",
+            "True", "try", "while", "with", "yield",
+        ],
+        line_comment: "#",
+        quotes: &['"', '\''],
+    },
+    Language {
+        names: &["c", "cpp", "c++", "h"],
+        keywords: &[
+            "auto", "break", "case", "char", "class", "const", "continue", "default", "do",
+            "double", "else", "enum", "extern", "float", "for", "goto", "if", "int", "long",
+            "return", "short", "signed", "sizeof", "static", "struct", "switch", "typedef",
+            "union", "unsigned", "void", "volatile", "while",
+        ],
+        line_comment: "//",
+        quotes: &['"', '\''],
+    },
+    Language {
+        names: &["javascript", "js", "typescript", "ts"],
+        keywords: &[
+            "async", "await", "break", "case", "catch", "class", "const", "continue", "default",
+            "delete", "do", "else", "export", "extends", "false", "finally", "for", "function",
+            "if", "import", "in", "let", "new", "null", "return", "switch", "this", "throw",
+            "true", "try", "typeof", "var", "void", "while", "yield",
+        ],
+        line_comment: "//",
+        quotes: &['"', '\'', '`'],
+    },
+    Language {
+        names: &["shell", "sh", "bash"],
+        keywords: &[
+            "case", "do", "done", "elif", "else", "esac", "export", "fi", "for", "function", "if",
+            "in", "local", "return", "then", "until", "while",
+        ],
+        line_comment: "#",
+        quotes: &['"', '\''],
+    },
+];
+
+fn language(hint: &str) -> Option<&'static Language> {
+    let hint = hint.to_ascii_lowercase();
+    LANGUAGES
+        .iter()
+        .find(|lang| lang.names.contains(&hint.as_str()))
+}
+
+const STYLE: &str = "\
+body { margin: 0; background: #fdfdfd; }
+table { border-collapse: collapse; font-family: monospace; }
+td.ln { color: #999; padding: 0 1em; text-align: right; user-select: none; }
+td.code { white-space: pre; }
+.kw { color: #a626a4; font-weight: bold; }
+.str { color: #50a14f; }
+.num { color: #986801; }
+.com { color: #a0a1a7; font-style: italic; }
+";
+
+/// Renders `text` as an HTML page, highlighting it as the language named by `lang` if known.
+pub fn render_html(title: &str, text: &str, lang: Option<&str>) -> String {
+    let lang = lang.and_then(language);
+    let mut html = String::new();
+    html.push_str("<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>");
+    escape_into(&mut html, title);
+    let _ = writeln!(html, "</title><style>{STYLE}</style></head><body><table>");
+    for (i, line) in text.lines().enumerate() {
+        let _ = write!(
+            html,
+            "<tr><td class=\"ln\">{}</td><td class=\"code\">",
+            i + 1
+        );
+        match lang {
+            Some(lang) => highlight_line(&mut html, line, lang),
+            None => escape_into(&mut html, line),
+        }
+        html.push_str("</td></tr>\n");
+    }
+    html.push_str("</table></body></html>\n");
+    html
+}
+
+fn highlight_line(html: &mut String, line: &str, lang: &Language) {
+    let mut rest = line;
+    while let Some(c) = rest.chars().next() {
+        if rest.starts_with(lang.line_comment) {
+            span(html, "com", rest);
+            return;
+        }
+        let len = if lang.quotes.contains(&c) {
+            let len = string_len(rest, c);
+            span(html, "str", &rest[..len]);
+            len
+        } else if c.is_ascii_digit() {
+            let len = word_len(rest);
+            span(html, "num", &rest[..len]);
+            len
+        } else if c.is_alphabetic() || c == '_' {
+            let len = word_len(rest);
+            let word = &rest[..len];
+            if lang.keywords.contains(&word) {
+                span(html, "kw", word);
+            } else {
+                escape_into(html, word);
+            }
+            len
+        } else {
+            escape_into(html, &rest[..c.len_utf8()]);
+            c.len_utf8()
+        };
+        rest = &rest[len..];
+    }
+}
+
+/// The length of the string literal at the start of `s`, up to the end of the line if unterminated.
+fn string_len(s: &str, quote: char) -> usize {
+    let mut escaped = false;
+    for (i, c) in s.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            _ if c == quote => return i + c.len_utf8(),
+            _ => {}
+        }
+    }
+    s.len()
+}
+
+fn word_len(s: &str) -> usize {
+    s.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+        .unwrap_or(s.len())
+}
+
+fn span(html: &mut String, class: &str, text: &str) {
+    let _ = write!(html, "<span class=\"{class}\">");
+    escape_into(html, text);
+    html.push_str("</span>");
+}
+
+fn escape_into(html: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '&' => html.push_str("&amp;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            _ => html.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_highlight_rust() {
+        let html = render_html("t", "let x = \"<a>\"; // 42", Some("rust"));
+        assert!(html.contains(
+            "<td class=\"ln\">1</td><td class=\"code\"><span class=\"kw\">let</span> x = \
+             <span class=\"str\">&quot;&lt;a&gt;&quot;</span>; \
+             <span class=\"com\">// 42</span></td>"
+        ));
+    }
+
+    #[test]
+    fn test_unknown_language_is_escaped_only() {
+        let html = render_html("t", "fn <b>\nsecond", Some("klingon"));
+        assert!(html.contains("<td class=\"code\">fn &lt;b&gt;</td>"));
+        assert!(html.contains("<td class=\"ln\">2</td><td class=\"code\">second</td>"));
+    }
+}
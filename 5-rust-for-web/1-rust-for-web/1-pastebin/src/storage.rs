@@ -0,0 +1,1065 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    fmt,
+    fs::{self, File},
+    hash::{Hash, Hasher},
+    io::{self, BufReader, BufWriter, Cursor, IntoInnerError, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    time::SystemTime,
+};
+
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::{
+    compress,
+    paste::{BodyReader, Encoding, Metadata, Paste, Revision},
+};
+
+/// A place where pastes are kept.
+///
+/// Storages are shared between all request handlers, so every method takes `&self` and
+/// implementations do their own, fine-grained locking.
+pub trait Storage: Send + Sync {
+    /// Reads a body that is about to be stored, hashing it and gzipping it if it grows beyond
+    /// `compress_above` bytes. Bodies are written out as they are read, so this blocks.
+    ///
+    /// Nothing is stored until the result is passed to [`Storage::insert`] or
+    /// [`Storage::add_revision`].
+    fn stage(&self, body: &mut dyn Read, compress_above: Option<usize>) -> io::Result<Staged>;
+    /// Stores a new paste. `meta` must have exactly one revision, describing `body`.
+    fn insert(&self, uuid: Uuid, meta: Metadata, body: Staged) -> io::Result<()>;
+    /// Adds a revision to an existing paste, returning its number, or `None` if there is no
+    /// such paste.
+    fn add_revision(
+        &self,
+        uuid: &Uuid,
+        revision: Revision,
+        body: Staged,
+    ) -> io::Result<Option<u32>>;
+    /// Opens the given revision of a paste, or its latest revision if `revision` is `None`.
+    ///
+    /// The body is read the way it is stored, which is not necessarily the way it was staged:
+    /// a body identical to one already stored shares its encoding. Once opened, it can be read
+    /// to the end even if the paste is removed in the meantime.
+    fn open(
+        &self,
+        uuid: &Uuid,
+        revision: Option<u32>,
+    ) -> io::Result<Option<Paste<Box<dyn BodyReader>>>>;
+    /// Like [`Storage::open`], but reads the whole body into memory.
+    fn get(&self, uuid: &Uuid, revision: Option<u32>) -> io::Result<Option<Paste>> {
+        self.open(uuid, revision)?.map(Paste::read).transpose()
+    }
+    /// The metadata of a paste, without opening any of its bodies.
+    fn meta(&self, uuid: &Uuid) -> io::Result<Option<Metadata>>;
+    /// Removes a paste. Of several concurrent removals of the same paste, only one gets its
+    /// metadata back.
+    fn remove(&self, uuid: &Uuid) -> io::Result<Option<Metadata>>;
+    /// Removes every paste that has expired at `now`, returning how many were removed.
+    fn remove_expired(&self, now: SystemTime) -> io::Result<usize>;
+    /// The combined size of all stored bodies in bytes, as encoded and counting identical
+    /// bodies once.
+    fn total_bytes(&self) -> u64;
+    /// The metadata of every stored paste, in no particular order.
+    fn list(&self) -> io::Result<Vec<(Uuid, Metadata)>>;
+    /// Makes sure everything stored so far survives a crash.
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A body read by [`Storage::stage`] that is not part of any paste yet. Dropping it throws it
+/// away.
+pub struct Staged {
+    /// The SHA-256 of the body in hex; see [`Revision::hash`].
+    pub hash: String,
+    /// The length of the body in bytes.
+    pub size: u64,
+    pub encoding: Encoding,
+    /// The length of the body in bytes as stored, which is what counts towards the quota.
+    pub stored_len: u64,
+    data: StagedData,
+}
+
+enum StagedData {
+    Memory(Bytes),
+    File(TempFile),
+}
+
+impl Staged {
+    /// Describes the body as a new revision.
+    pub fn revision(&self, content_type: impl Into<String>) -> Revision {
+        Revision {
+            content_type: content_type.into(),
+            size: self.size,
+            hash: self.hash.clone(),
+            created_at: SystemTime::now(),
+            nonce: None,
+        }
+    }
+}
+
+/// Hashes and counts everything read through it.
+struct HashingReader<'a> {
+    inner: &'a mut dyn Read,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl Read for HashingReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.size += n as u64;
+        Ok(n)
+    }
+}
+
+/// Copies `body` into `sink` as [`Storage::stage`] describes, returning the sink along with the
+/// body's hash, size and encoding.
+fn stage_into<W: Write>(
+    body: &mut dyn Read,
+    mut sink: W,
+    compress_above: Option<usize>,
+) -> io::Result<(W, String, u64, Encoding)> {
+    let mut body = HashingReader {
+        inner: body,
+        hasher: Sha256::new(),
+        size: 0,
+    };
+    let (sink, encoding) = match compress_above {
+        Some(threshold) => compress::encode(&mut body, sink, threshold)?,
+        None => {
+            io::copy(&mut body, &mut sink)?;
+            (sink, Encoding::Identity)
+        }
+    };
+    let hash = format!("{:x}", body.hasher.finalize());
+    Ok((sink, hash, body.size, encoding))
+}
+
+fn staged_elsewhere() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "body was staged by another storage",
+    )
+}
+
+/// How many independently locked parts each storage's index is split into.
+const SHARDS: usize = 16;
+
+/// A map split into shards by key, so that requests for different pastes rarely wait on
+/// each other.
+///
+/// A panic while a shard is locked poisons the lock. Every operation on a shard leaves it
+/// consistent before it can panic, so poisoned locks are simply taken over rather than taking
+/// the whole storage down with them.
+///
+/// Storages that lock a paste's shard and then a body's shard always do so in that order.
+struct Shards<T> {
+    shards: Vec<RwLock<T>>,
+}
+
+impl<T: Default> Default for Shards<T> {
+    fn default() -> Self {
+        Shards {
+            shards: (0..SHARDS).map(|_| RwLock::default()).collect(),
+        }
+    }
+}
+
+impl<T> Shards<T> {
+    fn shard(&self, key: &(impl Hash + ?Sized)) -> &RwLock<T> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+
+    fn read(&self, key: &(impl Hash + ?Sized)) -> RwLockReadGuard<'_, T> {
+        self.shard(key)
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self, key: &(impl Hash + ?Sized)) -> RwLockWriteGuard<'_, T> {
+        self.shard(key)
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Read-locks each shard in turn, never more than one at a time.
+    fn read_all(&self) -> impl Iterator<Item = RwLockReadGuard<'_, T>> {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Write-locks each shard in turn, never more than one at a time.
+    fn write_all(&self) -> impl Iterator<Item = RwLockWriteGuard<'_, T>> {
+        self.shards
+            .iter()
+            .map(|shard| shard.write().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+/// Resolves a requested revision number against `meta`, `None` meaning the latest.
+fn revision_number(meta: &Metadata, revision: Option<u32>) -> Option<u32> {
+    match revision {
+        Some(revision) if revision > meta.latest() => None,
+        Some(revision) => Some(revision),
+        None => Some(meta.latest()),
+    }
+}
+
+fn missing_body(hash: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("missing body {hash}"))
+}
+
+/// A body shared by every revision with the same content.
+struct Blob {
+    body: Bytes,
+    encoding: Encoding,
+    /// How many revisions, across all pastes, have this body.
+    refs: usize,
+}
+
+/// Keeps every paste in memory. Everything is lost when the process exits.
+#[derive(Default)]
+pub struct MemoryStorage {
+    pastes: Shards<HashMap<Uuid, Metadata>>,
+    /// Keyed by content hash.
+    blobs: Shards<HashMap<String, Blob>>,
+    bytes: AtomicU64,
+}
+
+impl MemoryStorage {
+    /// Takes a reference to a staged body, storing it unless it is stored already.
+    fn add_ref(&self, body: &Staged) -> io::Result<()> {
+        let StagedData::Memory(data) = &body.data else {
+            return Err(staged_elsewhere());
+        };
+        let mut blobs = self.blobs.write(&body.hash);
+        let blob = blobs.entry(body.hash.clone()).or_insert_with(|| {
+            self.bytes.fetch_add(body.stored_len, Ordering::Relaxed);
+            Blob {
+                body: data.clone(),
+                encoding: body.encoding,
+                refs: 0,
+            }
+        });
+        blob.refs += 1;
+        Ok(())
+    }
+
+    /// Drops a reference to the body of `revision`, freeing it if it was the last one.
+    fn drop_ref(&self, revision: &Revision) {
+        let mut blobs = self.blobs.write(&revision.hash);
+        if let Some(blob) = blobs.get_mut(&revision.hash) {
+            blob.refs -= 1;
+            if blob.refs == 0 {
+                self.bytes
+                    .fetch_sub(blob.body.len() as u64, Ordering::Relaxed);
+                blobs.remove(&revision.hash);
+            }
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn stage(&self, body: &mut dyn Read, compress_above: Option<usize>) -> io::Result<Staged> {
+        let (body, hash, size, encoding) = stage_into(body, Vec::new(), compress_above)?;
+        Ok(Staged {
+            hash,
+            size,
+            encoding,
+            stored_len: body.len() as u64,
+            data: StagedData::Memory(body.into()),
+        })
+    }
+
+    fn insert(&self, uuid: Uuid, meta: Metadata, body: Staged) -> io::Result<()> {
+        debug_assert_eq!(meta.revisions.len(), 1);
+        debug_assert_eq!(meta.revisions[0].hash, body.hash);
+        let mut pastes = self.pastes.write(&uuid);
+        self.add_ref(&body)?;
+        if let Some(old) = pastes.insert(uuid, meta) {
+            old.revisions
+                .iter()
+                .for_each(|revision| self.drop_ref(revision));
+        }
+        Ok(())
+    }
+
+    fn add_revision(
+        &self,
+        uuid: &Uuid,
+        revision: Revision,
+        body: Staged,
+    ) -> io::Result<Option<u32>> {
+        debug_assert_eq!(revision.hash, body.hash);
+        let mut pastes = self.pastes.write(uuid);
+        let Some(meta) = pastes.get_mut(uuid) else {
+            return Ok(None);
+        };
+        self.add_ref(&body)?;
+        meta.revisions.push(revision);
+        Ok(Some(meta.latest()))
+    }
+
+    fn open(
+        &self,
+        uuid: &Uuid,
+        revision: Option<u32>,
+    ) -> io::Result<Option<Paste<Box<dyn BodyReader>>>> {
+        let pastes = self.pastes.read(uuid);
+        let Some(meta) = pastes.get(uuid) else {
+            return Ok(None);
+        };
+        let Some(revision) = revision_number(meta, revision) else {
+            return Ok(None);
+        };
+        let hash = &meta.revisions[revision as usize].hash;
+        let (body, encoding) = match self.blobs.read(hash).get(hash) {
+            Some(blob) => (blob.body.clone(), blob.encoding),
+            None => return Err(missing_body(hash)),
+        };
+        Ok(Some(Paste {
+            meta: meta.clone(),
+            revision,
+            body: Box::new(Cursor::new(body)),
+            encoding,
+        }))
+    }
+
+    fn meta(&self, uuid: &Uuid) -> io::Result<Option<Metadata>> {
+        Ok(self.pastes.read(uuid).get(uuid).cloned())
+    }
+
+    fn remove(&self, uuid: &Uuid) -> io::Result<Option<Metadata>> {
+        let mut pastes = self.pastes.write(uuid);
+        let meta = pastes.remove(uuid);
+        if let Some(meta) = &meta {
+            meta.revisions
+                .iter()
+                .for_each(|revision| self.drop_ref(revision));
+        }
+        Ok(meta)
+    }
+
+    fn remove_expired(&self, now: SystemTime) -> io::Result<usize> {
+        let mut removed = 0;
+        for mut pastes in self.pastes.write_all() {
+            pastes.retain(|_, meta| {
+                let expired = meta.is_expired(now);
+                if expired {
+                    meta.revisions
+                        .iter()
+                        .for_each(|revision| self.drop_ref(revision));
+                    removed += 1;
+                }
+                !expired
+            });
+        }
+        Ok(removed)
+    }
+
+    fn total_bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    fn list(&self) -> io::Result<Vec<(Uuid, Metadata)>> {
+        Ok(self
+            .pastes
+            .read_all()
+            .flat_map(|pastes| {
+                pastes
+                    .iter()
+                    .map(|(uuid, meta)| (*uuid, meta.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect())
+    }
+}
+
+/// Keeps pastes as files in a directory: `<uuid>.json` with the metadata of each paste, and
+/// `<hash>.blob` (or `<hash>.gz` if compressed) with each distinct body, named after its
+/// content hash. Staged bodies are written to `.tmp` files until they are stored.
+///
+/// Reference counts are not stored but recounted from the metadata on open, so they can never
+/// disagree with it after a crash.
+///
+/// The layouts of earlier, unreleased versions are not migrated. Their files are left where they
+/// are and ignored, with a warning.
+pub struct DiskStorage {
+    dir: PathBuf,
+    shards: Shards<DiskShard>,
+    /// Keyed by content hash.
+    blobs: Shards<HashMap<String, BlobFile>>,
+    bytes: AtomicU64,
+}
+
+struct BlobFile {
+    encoding: Encoding,
+    /// The length of the file in bytes.
+    len: u64,
+    /// How many revisions, across all pastes, have this body.
+    refs: usize,
+}
+
+#[derive(Default)]
+struct DiskShard {
+    /// The metadata of every paste on disk, so only bodies have to be read on demand.
+    index: HashMap<Uuid, Metadata>,
+    /// Pastes written since the last flush.
+    dirty: HashSet<Uuid>,
+}
+
+impl DiskStorage {
+    /// Opens (or creates) the storage directory and recovers the pastes stored in it.
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        let storage = DiskStorage {
+            dir,
+            shards: Shards::default(),
+            blobs: Shards::default(),
+            bytes: AtomicU64::new(0),
+        };
+        let mut index = HashMap::new();
+        let mut blobs = HashMap::new();
+        for entry in fs::read_dir(&storage.dir)? {
+            let path = entry?.path();
+            match path.extension().and_then(|ext| ext.to_str()) {
+                // Left over from a write that was interrupted before the rename.
+                Some("tmp") => fs::remove_file(&path)?,
+                Some("json") => match file_uuid(&path) {
+                    Some(uuid) => match serde_json::from_slice::<Metadata>(&fs::read(&path)?) {
+                        Ok(meta) => {
+                            index.insert(uuid, meta);
+                        }
+                        Err(e) => ignore(&path, e),
+                    },
+                    None => ignore(&path, "not named after a paste"),
+                },
+                Some(ext @ ("blob" | "gz")) => {
+                    let encoding = match ext {
+                        "gz" => Encoding::Gzip,
+                        _ => Encoding::Identity,
+                    };
+                    let hash = path.file_stem().and_then(|stem| stem.to_str());
+                    match hash {
+                        Some(hash) if !blobs.contains_key(hash) => {
+                            let len = fs::metadata(&path)?.len();
+                            blobs.insert(hash.to_string(), (encoding, len, path));
+                        }
+                        // The same body twice, or not a body at all.
+                        _ => fs::remove_file(&path)?,
+                    }
+                }
+                _ => ignore(&path, "not part of the storage layout"),
+            }
+        }
+
+        for (uuid, meta) in index {
+            for revision in &meta.revisions {
+                let Some((encoding, len, _)) = blobs.get(&revision.hash) else {
+                    continue;
+                };
+                let mut files = storage.blobs.write(&revision.hash);
+                let file = files.entry(revision.hash.clone()).or_insert_with(|| {
+                    storage.bytes.fetch_add(*len, Ordering::Relaxed);
+                    BlobFile {
+                        encoding: *encoding,
+                        len: *len,
+                        refs: 0,
+                    }
+                });
+                file.refs += 1;
+            }
+            storage.shards.write(&uuid).index.insert(uuid, meta);
+        }
+        // A body nothing refers to was either never completely stored or not completely
+        // removed.
+        for (hash, (_, _, path)) in blobs {
+            if !storage.blobs.read(&hash).contains_key(&hash) {
+                fs::remove_file(path)?;
+            }
+        }
+
+        Ok(storage)
+    }
+
+    fn meta_path(&self, uuid: &Uuid) -> PathBuf {
+        self.dir.join(format!("{}.json", uuid.hyphenated()))
+    }
+
+    fn blob_path(&self, hash: &str, encoding: Encoding) -> PathBuf {
+        match encoding {
+            Encoding::Identity => self.dir.join(format!("{hash}.blob")),
+            Encoding::Gzip => self.dir.join(format!("{hash}.gz")),
+        }
+    }
+
+    /// Takes a reference to a staged body, moving it into place unless it is stored already.
+    fn add_ref(&self, body: &mut Staged) -> io::Result<()> {
+        let StagedData::File(staged) = &mut body.data else {
+            return Err(staged_elsewhere());
+        };
+        let mut files = self.blobs.write(&body.hash);
+        if let Some(file) = files.get_mut(&body.hash) {
+            file.refs += 1;
+            return Ok(());
+        }
+        staged.persist(&self.blob_path(&body.hash, body.encoding))?;
+        self.bytes.fetch_add(body.stored_len, Ordering::Relaxed);
+        let file = BlobFile {
+            encoding: body.encoding,
+            len: body.stored_len,
+            refs: 1,
+        };
+        files.insert(body.hash.clone(), file);
+        Ok(())
+    }
+
+    /// Drops a reference to the body of `revision`, deleting it if it was the last one.
+    fn drop_ref(&self, revision: &Revision) -> io::Result<()> {
+        let mut files = self.blobs.write(&revision.hash);
+        let Some(file) = files.get_mut(&revision.hash) else {
+            return Ok(());
+        };
+        file.refs -= 1;
+        if file.refs == 0 {
+            let (encoding, len) = (file.encoding, file.len);
+            files.remove(&revision.hash);
+            self.bytes.fetch_sub(len, Ordering::Relaxed);
+            fs::remove_file(self.blob_path(&revision.hash, encoding))?;
+        }
+        Ok(())
+    }
+
+    /// Where the body with the given hash is, and how it is encoded.
+    fn find_blob(&self, hash: &str) -> io::Result<(PathBuf, Encoding)> {
+        match self.blobs.read(hash).get(hash) {
+            Some(file) => Ok((self.blob_path(hash, file.encoding), file.encoding)),
+            None => Err(missing_body(hash)),
+        }
+    }
+
+    /// Stores `meta` as the metadata of a paste whose shard is write-locked by the caller,
+    /// taking back the reference to the new body if that fails.
+    fn commit(&self, uuid: &Uuid, meta: &Metadata, new: &Revision) -> io::Result<()> {
+        let written = serde_json::to_vec(meta)
+            .map_err(io::Error::from)
+            .and_then(|json| write_atomic(&self.meta_path(uuid), &json));
+        if let Err(e) = written {
+            self.drop_ref(new)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Deletes a paste whose shard is write-locked by the caller.
+    fn remove_locked(&self, shard: &mut DiskShard, uuid: &Uuid) -> io::Result<Option<Metadata>> {
+        let Some(meta) = shard.index.remove(uuid) else {
+            return Ok(None);
+        };
+        shard.dirty.remove(uuid);
+        // Once the metadata is gone the paste is; its bodies are cleaned up on open if we crash
+        // before getting to them.
+        fs::remove_file(self.meta_path(uuid))?;
+        for revision in &meta.revisions {
+            self.drop_ref(revision)?;
+        }
+        Ok(Some(meta))
+    }
+}
+
+/// The UUID of the paste a `<uuid>.<ext>` file belongs to.
+fn file_uuid(path: &Path) -> Option<Uuid> {
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// Leaves a file that [`DiskStorage::open`] does not understand alone.
+fn ignore(path: &Path, reason: impl fmt::Display) {
+    tracing::warn!("ignoring {}: {reason}", path.display());
+}
+
+/// A file that is removed when dropped, unless it has been moved into place.
+struct TempFile(Option<PathBuf>);
+
+impl TempFile {
+    fn persist(&mut self, path: &Path) -> io::Result<()> {
+        if let Some(tmp) = &self.0 {
+            fs::rename(tmp, path)?;
+            self.0 = None;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Some(tmp) = &self.0 {
+            let _ = fs::remove_file(tmp);
+        }
+    }
+}
+
+/// Writes to a temporary file first so that a crash never leaves a half-written file behind.
+/// The file is on disk once this returns.
+fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents)?;
+    // Otherwise the rename may reach the disk before the contents do.
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    sync_parent(path)
+}
+
+/// Makes the creation, renaming or removal of `path` survive a crash.
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) => File::open(dir)?.sync_all(),
+        None => Ok(()),
+    }
+}
+
+impl Storage for DiskStorage {
+    fn stage(&self, body: &mut dyn Read, compress_above: Option<usize>) -> io::Result<Staged> {
+        // Named like the temporary files of `write_atomic`, so a crash leaves nothing behind
+        // that `open` does not clean up.
+        let tmp = self.dir.join(format!("{}.tmp", Uuid::new_v4().simple()));
+        let staged = TempFile(Some(tmp.clone()));
+        let sink = BufWriter::new(File::create(&tmp)?);
+        let (sink, hash, size, encoding) = stage_into(body, sink, compress_above)?;
+        let file = sink.into_inner().map_err(IntoInnerError::into_error)?;
+        // Synced before it can be moved into place, as metadata referring to it is synced
+        // right after.
+        file.sync_all()?;
+        Ok(Staged {
+            hash,
+            size,
+            encoding,
+            stored_len: file.metadata()?.len(),
+            data: StagedData::File(staged),
+        })
+    }
+
+    fn insert(&self, uuid: Uuid, meta: Metadata, mut body: Staged) -> io::Result<()> {
+        debug_assert_eq!(meta.revisions.len(), 1);
+        debug_assert_eq!(meta.revisions[0].hash, body.hash);
+        let mut shard = self.shards.write(&uuid);
+        // The metadata goes last: a paste only exists once its metadata file does.
+        self.add_ref(&mut body)?;
+        self.commit(&uuid, &meta, &meta.revisions[0])?;
+        if let Some(old) = shard.index.insert(uuid, meta) {
+            for revision in &old.revisions {
+                self.drop_ref(revision)?;
+            }
+        }
+        shard.dirty.insert(uuid);
+        Ok(())
+    }
+
+    fn add_revision(
+        &self,
+        uuid: &Uuid,
+        revision: Revision,
+        mut body: Staged,
+    ) -> io::Result<Option<u32>> {
+        debug_assert_eq!(revision.hash, body.hash);
+        let mut shard = self.shards.write(uuid);
+        let Some(meta) = shard.index.get(uuid) else {
+            return Ok(None);
+        };
+        let mut meta = meta.clone();
+        // As with new pastes, the revision only exists once the metadata lists it.
+        self.add_ref(&mut body)?;
+        meta.revisions.push(revision);
+        self.commit(uuid, &meta, meta.latest_revision())?;
+        let latest = meta.latest();
+        shard.index.insert(*uuid, meta);
+        shard.dirty.insert(*uuid);
+        Ok(Some(latest))
+    }
+
+    fn open(
+        &self,
+        uuid: &Uuid,
+        revision: Option<u32>,
+    ) -> io::Result<Option<Paste<Box<dyn BodyReader>>>> {
+        // Holding the lock while opening keeps the body from being removed before it is open.
+        // After that, removing it only unlinks the file, which stays readable until closed.
+        let shard = self.shards.read(uuid);
+        let Some(meta) = shard.index.get(uuid) else {
+            return Ok(None);
+        };
+        let Some(revision) = revision_number(meta, revision) else {
+            return Ok(None);
+        };
+        let (path, encoding) = self.find_blob(&meta.revisions[revision as usize].hash)?;
+        Ok(Some(Paste {
+            meta: meta.clone(),
+            revision,
+            body: Box::new(BufReader::new(File::open(path)?)),
+            encoding,
+        }))
+    }
+
+    fn meta(&self, uuid: &Uuid) -> io::Result<Option<Metadata>> {
+        Ok(self.shards.read(uuid).index.get(uuid).cloned())
+    }
+
+    fn remove(&self, uuid: &Uuid) -> io::Result<Option<Metadata>> {
+        self.remove_locked(&mut self.shards.write(uuid), uuid)
+    }
+
+    fn remove_expired(&self, now: SystemTime) -> io::Result<usize> {
+        let mut removed = 0;
+        for mut shard in self.shards.write_all() {
+            let expired: Vec<Uuid> = shard
+                .index
+                .iter()
+                .filter(|(_, meta)| meta.is_expired(now))
+                .map(|(uuid, _)| *uuid)
+                .collect();
+            for uuid in &expired {
+                self.remove_locked(&mut shard, uuid)?;
+            }
+            removed += expired.len();
+        }
+        Ok(removed)
+    }
+
+    fn total_bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    fn list(&self) -> io::Result<Vec<(Uuid, Metadata)>> {
+        Ok(self
+            .shards
+            .read_all()
+            .flat_map(|shard| {
+                shard
+                    .index
+                    .iter()
+                    .map(|(uuid, meta)| (*uuid, meta.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        for mut shard in self.shards.write_all() {
+            for uuid in &shard.dirty {
+                File::open(self.meta_path(uuid))?.sync_all()?;
+                for revision in &shard.index[uuid].revisions {
+                    File::open(self.find_blob(&revision.hash)?.0)?.sync_all()?;
+                }
+            }
+            shard.dirty.clear();
+        }
+        // Syncing the directory persists the renames and removals.
+        File::open(&self.dir)?.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::paste::{content_hash, DEFAULT_CONTENT_TYPE};
+
+    /// Stages and stores the body of `paste`, which must have a single revision.
+    fn insert(storage: &dyn Storage, uuid: Uuid, paste: Paste) {
+        let body = storage.stage(&mut &paste.body[..], None).unwrap();
+        storage.insert(uuid, paste.meta, body).unwrap();
+    }
+
+    fn add_revision(storage: &dyn Storage, uuid: &Uuid, body: &str) -> Option<u32> {
+        let body = storage.stage(&mut body.as_bytes(), None).unwrap();
+        let revision = body.revision("text/x-rust");
+        storage.add_revision(uuid, revision, body).unwrap()
+    }
+
+    fn round_trip(storage: &dyn Storage) {
+        let uuid = Uuid::new_v4();
+        let paste = Paste::new("hello", DEFAULT_CONTENT_TYPE);
+        assert_eq!(storage.get(&uuid, None).unwrap(), None);
+        insert(storage, uuid, paste.clone());
+        assert_eq!(storage.total_bytes(), 5);
+        assert_eq!(storage.get(&uuid, None).unwrap().as_ref(), Some(&paste));
+        assert_eq!(storage.meta(&uuid).unwrap().as_ref(), Some(&paste.meta));
+        assert_eq!(storage.remove(&uuid).unwrap().as_ref(), Some(&paste.meta));
+        assert_eq!(storage.get(&uuid, None).unwrap(), None);
+        assert_eq!(storage.meta(&uuid).unwrap(), None);
+        assert_eq!(storage.remove(&uuid).unwrap(), None);
+        assert_eq!(storage.total_bytes(), 0);
+    }
+
+    fn revisions(storage: &dyn Storage) {
+        let uuid = Uuid::new_v4();
+        insert(storage, uuid, Paste::new("first", DEFAULT_CONTENT_TYPE));
+        assert_eq!(add_revision(storage, &uuid, "second"), Some(1));
+        assert_eq!(add_revision(storage, &Uuid::new_v4(), "second"), None);
+        assert_eq!(storage.total_bytes(), 11);
+
+        let latest = storage.get(&uuid, None).unwrap().unwrap();
+        assert_eq!((latest.revision, latest.text()), (1, Some("second")));
+        assert_eq!(latest.current().content_type, "text/x-rust");
+        let first = storage.get(&uuid, Some(0)).unwrap().unwrap();
+        assert_eq!((first.revision, first.text()), (0, Some("first")));
+        assert_eq!(storage.get(&uuid, Some(2)).unwrap(), None);
+
+        storage.remove(&uuid).unwrap();
+        assert_eq!(storage.total_bytes(), 0);
+    }
+
+    fn expiry(storage: &dyn Storage) {
+        let now = SystemTime::now();
+        let (fresh, stale, forever) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let with_expiry = |expires_at| {
+            let mut paste = Paste::new("text", DEFAULT_CONTENT_TYPE);
+            paste.meta.expires_at = Some(expires_at);
+            paste
+        };
+        insert(storage, fresh, with_expiry(now + Duration::from_secs(60)));
+        insert(storage, stale, with_expiry(now - Duration::from_secs(60)));
+        insert(storage, forever, Paste::new("text", DEFAULT_CONTENT_TYPE));
+
+        assert_eq!(storage.remove_expired(now).unwrap(), 1);
+        // The remaining pastes share their body.
+        assert_eq!(storage.total_bytes(), 4);
+        let mut listed: Vec<Uuid> = storage
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|(u, _)| u)
+            .collect();
+        listed.sort();
+        let mut expected = vec![fresh, forever];
+        expected.sort();
+        assert_eq!(listed, expected);
+        assert!(storage.get(&fresh, None).unwrap().is_some());
+        assert!(storage.get(&stale, None).unwrap().is_none());
+        assert!(storage.get(&forever, None).unwrap().is_some());
+    }
+
+    fn dedup(storage: &dyn Storage) {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        insert(storage, a, Paste::new("same log", DEFAULT_CONTENT_TYPE));
+        insert(storage, b, Paste::new("same log", DEFAULT_CONTENT_TYPE));
+        add_revision(storage, &a, "same log");
+        assert_eq!(storage.total_bytes(), 8);
+
+        storage.remove(&a).unwrap();
+        assert_eq!(storage.total_bytes(), 8);
+        let paste = storage.get(&b, None).unwrap().unwrap();
+        assert_eq!(paste.text(), Some("same log"));
+        storage.remove(&b).unwrap();
+        assert_eq!(storage.total_bytes(), 0);
+    }
+
+    /// Stores a compressed body, then the same content uncompressed.
+    fn encoded(storage: &dyn Storage) -> Uuid {
+        let log = "ERROR connection reset\n".repeat(100);
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let compressed = storage.stage(&mut log.as_bytes(), Some(0)).unwrap();
+        let stored_len = compressed.stored_len;
+        assert_eq!(compressed.encoding, Encoding::Gzip);
+        assert!(stored_len < log.len() as u64 / 10);
+        let meta = Metadata::new(compressed.revision(DEFAULT_CONTENT_TYPE));
+        storage.insert(a, meta, compressed).unwrap();
+        insert(storage, b, Paste::new(log.clone(), DEFAULT_CONTENT_TYPE));
+        assert_eq!(storage.total_bytes(), stored_len);
+
+        let paste = storage.get(&b, None).unwrap().unwrap();
+        assert_eq!(paste.encoding, Encoding::Gzip);
+        assert_eq!(paste.body.len() as u64, stored_len);
+        assert_eq!(paste.decode().unwrap().text(), Some(log.as_str()));
+        b
+    }
+
+    /// Stages a body and throws it away.
+    fn staged(storage: &dyn Storage) {
+        let body = storage.stage(&mut &b"never stored"[..], None).unwrap();
+        assert_eq!(body.hash, content_hash(b"never stored"));
+        assert_eq!((body.size, body.stored_len), (12, 12));
+        drop(body);
+        assert_eq!(storage.total_bytes(), 0);
+    }
+
+    #[test]
+    fn test_memory_round_trip() {
+        round_trip(&MemoryStorage::default());
+    }
+
+    #[test]
+    fn test_memory_revisions() {
+        revisions(&MemoryStorage::default());
+    }
+
+    #[test]
+    fn test_memory_expiry() {
+        expiry(&MemoryStorage::default());
+    }
+
+    #[test]
+    fn test_memory_dedup() {
+        dedup(&MemoryStorage::default());
+    }
+
+    #[test]
+    fn test_memory_encoded() {
+        encoded(&MemoryStorage::default());
+    }
+
+    #[test]
+    fn test_memory_staged() {
+        staged(&MemoryStorage::default());
+    }
+
+    #[test]
+    fn test_disk_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        round_trip(&DiskStorage::open(dir.path()).unwrap());
+    }
+
+    #[test]
+    fn test_disk_revisions() {
+        let dir = tempfile::tempdir().unwrap();
+        revisions(&DiskStorage::open(dir.path()).unwrap());
+    }
+
+    #[test]
+    fn test_disk_expiry() {
+        let dir = tempfile::tempdir().unwrap();
+        expiry(&DiskStorage::open(dir.path()).unwrap());
+    }
+
+    #[test]
+    fn test_disk_dedup() {
+        let dir = tempfile::tempdir().unwrap();
+        dedup(&DiskStorage::open(dir.path()).unwrap());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_disk_encoded() {
+        let dir = tempfile::tempdir().unwrap();
+        let uuid = encoded(&DiskStorage::open(dir.path()).unwrap());
+        let storage = DiskStorage::open(dir.path()).unwrap();
+        assert_eq!(
+            storage.get(&uuid, None).unwrap().unwrap().encoding,
+            Encoding::Gzip
+        );
+    }
+
+    #[test]
+    fn test_disk_staged() {
+        let dir = tempfile::tempdir().unwrap();
+        staged(&DiskStorage::open(dir.path()).unwrap());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_disk_recovers_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let uuid = Uuid::new_v4();
+        let storage = DiskStorage::open(dir.path()).unwrap();
+        insert(
+            &storage,
+            uuid,
+            Paste::new("persisted", DEFAULT_CONTENT_TYPE),
+        );
+        storage.flush().unwrap();
+        drop(storage);
+        let orphan = dir.path().join(format!("{}.blob", content_hash(b"half")));
+        fs::write(&orphan, "half").unwrap();
+        fs::write(dir.path().join("garbage.tmp"), "half a paste").unwrap();
+
+        let storage = DiskStorage::open(dir.path()).unwrap();
+        let paste = storage.get(&uuid, None).unwrap().unwrap();
+        assert_eq!(paste.text(), Some("persisted"));
+        assert_eq!(storage.total_bytes(), 9);
+        assert!(!orphan.exists());
+        assert!(!dir.path().join("garbage.tmp").exists());
+    }
+
+    #[test]
+    fn test_disk_ignores_earlier_layouts() {
+        let dir = tempfile::tempdir().unwrap();
+        let uuid = Uuid::new_v4();
+        let storage = DiskStorage::open(dir.path()).unwrap();
+        insert(&storage, uuid, Paste::new("current", DEFAULT_CONTENT_TYPE));
+        drop(storage);
+        // Raw text under a bare UUID, and metadata without revisions next to its body.
+        let old = Uuid::new_v4();
+        let files = [
+            (Uuid::new_v4().to_string(), "just text".to_string()),
+            (
+                format!("{old}.json"),
+                r#"{"content_type":"text/plain","size":3,"delete_token":"t"}"#.to_string(),
+            ),
+            (format!("{old}.body"), "old".to_string()),
+        ];
+        for (name, contents) in &files {
+            fs::write(dir.path().join(name), contents).unwrap();
+        }
+
+        let storage = DiskStorage::open(dir.path()).unwrap();
+        assert_eq!(storage.list().unwrap().len(), 1);
+        let paste = storage.get(&uuid, None).unwrap().unwrap();
+        assert_eq!(paste.text(), Some("current"));
+        for (name, contents) in &files {
+            assert_eq!(
+                &fs::read_to_string(dir.path().join(name)).unwrap(),
+                contents
+            );
+        }
+    }
+
+    #[test]
+    fn test_concurrent_inserts() {
+        let storage = MemoryStorage::default();
+        std::thread::scope(|scope| {
+            for thread in 0..8 {
+                let storage = &storage;
+                scope.spawn(move || {
+                    for i in 0..100 {
+                        let body = format!("{thread:03}{i:03}");
+                        let paste = Paste::new(body, DEFAULT_CONTENT_TYPE);
+                        insert(storage, Uuid::new_v4(), paste);
+                    }
+                });
+            }
+        });
+        assert_eq!(storage.list().unwrap().len(), 800);
+        assert_eq!(storage.total_bytes(), 4800);
+    }
+
+    #[test]
+    fn test_poisoned_shard() {
+        let storage = MemoryStorage::default();
+        let uuid = Uuid::new_v4();
+        insert(&storage, uuid, Paste::new("text", DEFAULT_CONTENT_TYPE));
+        let panicked = std::panic::catch_unwind(|| {
+            let _shard = storage.pastes.write(&uuid);
+            panic!("handler bug");
+        });
+        assert!(panicked.is_err());
+        assert!(storage.pastes.shard(&uuid).is_poisoned());
+
+        assert!(storage.get(&uuid, None).unwrap().is_some());
+        assert!(storage.remove(&uuid).unwrap().is_some());
+    }
+}
@@ -0,0 +1,88 @@
+use pastebin::{
+    api::StoreOptions,
+    app::{self, AppState},
+    client::{Client, ClientError},
+    config::Limits,
+    crypto::Key,
+    storage::MemoryStorage,
+};
+use reqwest::StatusCode;
+
+/// Serves a fresh app on a random local port and returns its base URL.
+async fn serve() -> String {
+    let limits = Limits::default();
+    let app = app::router(
+        AppState::new(Box::<MemoryStorage>::default(), &limits),
+        &limits,
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    format!("http://{addr}")
+}
+
+#[tokio::test]
+async fn test_plain_paste() {
+    let client = Client::new(serve().await);
+    let response = client
+        .store("hello", "text/plain", &StoreOptions::default())
+        .await
+        .unwrap();
+    let url = client.paste_url(&response.uuid, None);
+    assert_eq!(client.load_url(&url).await.unwrap(), b"hello");
+    assert_eq!(client.load(&response.uuid, None).await.unwrap(), b"hello");
+
+    assert!(matches!(
+        client.delete(&response.uuid, "wrong").await,
+        Err(ClientError::Status(StatusCode::FORBIDDEN))
+    ));
+    client
+        .delete(&response.uuid, &response.token)
+        .await
+        .unwrap();
+    assert!(matches!(
+        client.load(&response.uuid, None).await,
+        Err(ClientError::Status(StatusCode::NOT_FOUND))
+    ));
+}
+
+#[tokio::test]
+async fn test_store_options() {
+    let client = Client::new(serve().await);
+    let options = StoreOptions {
+        burn: true,
+        ..StoreOptions::default()
+    };
+    let response = client.store("once", "text/plain", &options).await.unwrap();
+    assert_eq!(client.load(&response.uuid, None).await.unwrap(), b"once");
+    assert!(client.load(&response.uuid, None).await.is_err());
+}
+
+#[tokio::test]
+async fn test_encrypted_paste() {
+    let server = serve().await;
+    let client = Client::new(&server);
+    let (response, key) = client
+        .store_encrypted(b"top secret", &StoreOptions::default())
+        .await
+        .unwrap();
+
+    // The server only has the ciphertext.
+    let url = client.paste_url(&response.uuid, None);
+    let stored = reqwest::get(&url).await.unwrap().bytes().await.unwrap();
+    assert!(!stored.windows(6).any(|w| w == b"secret"));
+    let view = format!("{server}/view/{}", response.uuid);
+    assert_eq!(
+        reqwest::get(view).await.unwrap().status(),
+        StatusCode::UNSUPPORTED_MEDIA_TYPE
+    );
+
+    let url = client.paste_url(&response.uuid, Some(&key));
+    assert_eq!(client.load_url(&url).await.unwrap(), b"top secret");
+
+    let wrong = client.paste_url(&response.uuid, Some(&Key::generate()));
+    assert!(matches!(
+        client.load_url(&wrong).await,
+        Err(ClientError::Decrypt)
+    ));
+}
@@ -0,0 +1,471 @@
+use std::{collections::HashSet, sync::atomic::Ordering};
+
+use axum::{
+    body::{to_bytes, Body},
+    http::{header, Method, Request, StatusCode},
+    response::Response,
+    Router,
+};
+use pastebin::{
+    api::{PasteList, StoreResponse, UpdateResponse, DELETE_TOKEN_HEADER, NONCE_HEADER},
+    app::{self, AppState},
+    config::Limits,
+    paste::Encoding,
+    storage::{DiskStorage, MemoryStorage},
+};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+fn app() -> Router {
+    app_with(Limits::default())
+}
+
+fn app_with(limits: Limits) -> Router {
+    app::router(
+        AppState::new(Box::<MemoryStorage>::default(), &limits),
+        &limits,
+    )
+}
+
+async fn send(app: &Router, method: Method, uri: &str, body: &str) -> Response {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(header::CONTENT_TYPE, "text/plain")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    app.clone().oneshot(request).await.unwrap()
+}
+
+async fn body_bytes(response: Response) -> Vec<u8> {
+    to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap()
+        .to_vec()
+}
+
+async fn store(app: &Router, text: &str) -> StoreResponse {
+    let response = send(app, Method::POST, "/store", text).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    serde_json::from_slice(&body_bytes(response).await).unwrap()
+}
+
+#[tokio::test]
+async fn test_store_load_delete() {
+    let app = app();
+    let StoreResponse { uuid, token } = store(&app, "hello").await;
+
+    let response = send(&app, Method::GET, &format!("/load/{uuid}"), "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "text/plain");
+    assert_eq!(body_bytes(response).await, b"hello");
+
+    let delete = |token: &str| {
+        Request::builder()
+            .method(Method::DELETE)
+            .uri(format!("/delete/{uuid}"))
+            .header(DELETE_TOKEN_HEADER, token)
+            .body(Body::empty())
+            .unwrap()
+    };
+    let response = app.clone().oneshot(delete("wrong")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = app.clone().oneshot(delete(&token)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = send(&app, Method::GET, &format!("/load/{uuid}"), "").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_load_cannot_run_scripts() {
+    let app = app();
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri("/store")
+        .header(header::CONTENT_TYPE, "text/html")
+        .body(Body::from("<script>alert(document.cookie)</script>"))
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    let StoreResponse { uuid, .. } = serde_json::from_slice(&body_bytes(response).await).unwrap();
+
+    let response = send(&app, Method::GET, &format!("/load/{uuid}"), "").await;
+    let headers = response.headers();
+    assert_eq!(headers[header::CONTENT_TYPE], "text/html");
+    assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+    assert_eq!(headers[header::CONTENT_SECURITY_POLICY], "sandbox");
+}
+
+#[tokio::test]
+async fn test_update_and_diff() {
+    let app = app();
+    let StoreResponse { uuid, token } = store(&app, "one\ntwo\n").await;
+
+    let update = |token: &str| {
+        Request::builder()
+            .method(Method::PUT)
+            .uri(format!("/update/{uuid}"))
+            .header(DELETE_TOKEN_HEADER, token)
+            .body(Body::from("one\nthree\n"))
+            .unwrap()
+    };
+    let response = app.clone().oneshot(update("wrong")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = app.clone().oneshot(update(&token)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let UpdateResponse { revision } = serde_json::from_slice(&body_bytes(response).await).unwrap();
+    assert_eq!(revision, 1);
+
+    let response = send(&app, Method::GET, &format!("/load/{uuid}"), "").await;
+    assert_eq!(body_bytes(response).await, b"one\nthree\n");
+    let response = send(&app, Method::GET, &format!("/load/{uuid}?rev=0"), "").await;
+    assert_eq!(body_bytes(response).await, b"one\ntwo\n");
+    let response = send(&app, Method::GET, &format!("/load/{uuid}?rev=2"), "").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = send(&app, Method::GET, &format!("/diff/{uuid}"), "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let diff = String::from_utf8(body_bytes(response).await).unwrap();
+    assert!(
+        diff.starts_with("--- revision 0\n+++ revision 1\n"),
+        "{diff}"
+    );
+    assert!(diff.contains("\n-two\n+three\n"), "{diff}");
+}
+
+#[tokio::test]
+async fn test_diff_burn_after_read() {
+    let app = app();
+    let response = send(&app, Method::POST, "/store?burn=true", "one\n").await;
+    let StoreResponse { uuid, token } =
+        serde_json::from_slice(&body_bytes(response).await).unwrap();
+    let update = Request::builder()
+        .method(Method::PUT)
+        .uri(format!("/update/{uuid}"))
+        .header(DELETE_TOKEN_HEADER, token)
+        .body(Body::from("two\n"))
+        .unwrap();
+    let response = app.clone().oneshot(update).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = send(&app, Method::GET, &format!("/diff/{uuid}"), "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let diff = String::from_utf8(body_bytes(response).await).unwrap();
+    assert!(diff.contains("\n-one\n+two\n"), "{diff}");
+
+    // The diff was the one read allowed.
+    let response = send(&app, Method::GET, &format!("/diff/{uuid}"), "").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = send(&app, Method::GET, &format!("/load/{uuid}"), "").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_encrypted_paste_nonce() {
+    let app = app();
+    let store = |nonce: &str| {
+        Request::builder()
+            .method(Method::POST)
+            .uri("/store")
+            .header(NONCE_HEADER, nonce)
+            .body(Body::from("ciphertext"))
+            .unwrap()
+    };
+    let response = app.clone().oneshot(store("not a nonce")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let nonce = "AAECAwQFBgcICQoL";
+    let response = app.clone().oneshot(store(nonce)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let StoreResponse { uuid, .. } = serde_json::from_slice(&body_bytes(response).await).unwrap();
+    let response = send(&app, Method::GET, &format!("/load/{uuid}"), "").await;
+    assert_eq!(response.headers()[NONCE_HEADER], nonce);
+    assert_eq!(body_bytes(response).await, b"ciphertext");
+}
+
+#[tokio::test]
+async fn test_compressed_paste() {
+    let app = app();
+    let log = "WARN retrying in 5s\n".repeat(10_000);
+    let StoreResponse { uuid, .. } = store(&app, &log).await;
+
+    let load = |accept_encoding: &str| {
+        Request::builder()
+            .uri(format!("/load/{uuid}"))
+            .header(header::ACCEPT_ENCODING, accept_encoding)
+            .body(Body::empty())
+            .unwrap()
+    };
+    let response = app.clone().oneshot(load("gzip, br")).await.unwrap();
+    assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+    let compressed = body_bytes(response).await;
+    assert!(compressed.len() < log.len() / 10);
+    let decoded = pastebin::compress::decode(compressed, Encoding::Gzip).unwrap();
+    assert_eq!(decoded, log.as_bytes());
+
+    let response = app.clone().oneshot(load("identity")).await.unwrap();
+    assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+    assert_eq!(body_bytes(response).await, log.as_bytes());
+
+    let response = send(&app, Method::GET, "/search?q=retrying", "").await;
+    let list: PasteList = serde_json::from_slice(&body_bytes(response).await).unwrap();
+    assert_eq!(list.total, 1);
+}
+
+#[tokio::test]
+async fn test_unknown_uuid() {
+    let app = app();
+    let uuid = Uuid::new_v4();
+    for (method, uri) in [
+        (Method::GET, format!("/load/{uuid}")),
+        (Method::GET, format!("/view/{uuid}")),
+        (Method::GET, format!("/diff/{uuid}")),
+        (Method::PUT, format!("/update/{uuid}")),
+        (Method::DELETE, format!("/delete/{uuid}")),
+    ] {
+        let response = send(&app, method, &uri, "").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND, "{uri}");
+    }
+}
+
+#[tokio::test]
+async fn test_malformed_uuid() {
+    let app = app();
+    for (method, uri) in [
+        (Method::GET, "/load/not-a-uuid"),
+        (Method::GET, "/view/1234"),
+        (Method::DELETE, "/delete/not-a-uuid"),
+    ] {
+        let response = send(&app, method, uri, "").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{uri}");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_concurrent_writers() {
+    let app = app();
+    let n = 50;
+
+    let writers: Vec<_> = (0..n)
+        .map(|i| {
+            let app = app.clone();
+            tokio::spawn(async move { (i, store(&app, &format!("paste {i}")).await.uuid) })
+        })
+        .collect();
+    let mut uuids = HashSet::new();
+    for writer in writers {
+        let (i, uuid) = writer.await.unwrap();
+        let response = send(&app, Method::GET, &format!("/load/{uuid}"), "").await;
+        assert_eq!(body_bytes(response).await, format!("paste {i}").as_bytes());
+        uuids.insert(uuid);
+    }
+    assert_eq!(uuids.len(), n);
+
+    let response = send(&app, Method::GET, "/list", "").await;
+    let list: PasteList = serde_json::from_slice(&body_bytes(response).await).unwrap();
+    assert_eq!(list.total, n);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn test_concurrent_stores_within_quota() {
+    let limits = Limits {
+        store_quota: 1000,
+        ..Limits::default()
+    };
+    // Stores that write files leave plenty of time between checking the quota and storing.
+    let dir = tempfile::tempdir().unwrap();
+    let storage = DiskStorage::open(dir.path()).unwrap();
+    let app = app::router(AppState::new(Box::new(storage), &limits), &limits);
+    let paste = |i: usize| format!("{i:0100}");
+
+    let writers: Vec<_> = (0..200)
+        .map(|i| {
+            let app = app.clone();
+            tokio::spawn(async move { send(&app, Method::POST, "/store", &paste(i)).await })
+        })
+        .collect();
+    let mut stored = 0;
+    for writer in writers {
+        match writer.await.unwrap().status() {
+            StatusCode::OK => stored += 1,
+            status => assert_eq!(status, StatusCode::INSUFFICIENT_STORAGE),
+        }
+    }
+    assert!(stored <= 10, "{stored} pastes stored");
+
+    // Whatever was set aside for the refused pastes has been given back.
+    for i in 200.. {
+        let response = send(&app, Method::POST, "/store", &paste(i)).await;
+        if response.status() != StatusCode::OK {
+            assert_eq!(response.status(), StatusCode::INSUFFICIENT_STORAGE);
+            break;
+        }
+        stored += 1;
+    }
+    assert_eq!(stored, 10);
+}
+
+#[tokio::test]
+async fn test_search_skips_unreadable_pastes() {
+    let dir = tempfile::tempdir().unwrap();
+    let limits = Limits::default();
+    let storage = DiskStorage::open(dir.path()).unwrap();
+    let app = app::router(AppState::new(Box::new(storage), &limits), &limits);
+    store(&app, "needle in a lost haystack").await;
+    for entry in std::fs::read_dir(dir.path()).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_some_and(|ext| ext == "blob") {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+    let StoreResponse { uuid, .. } = store(&app, "needle in a haystack").await;
+
+    let response = send(&app, Method::GET, "/search?q=needle", "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let list: PasteList = serde_json::from_slice(&body_bytes(response).await).unwrap();
+    assert_eq!(list.total, 1);
+    assert_eq!(list.pastes[0].uuid, uuid);
+}
+
+#[tokio::test]
+async fn test_range_requests() {
+    let app = app();
+    let text = "0123456789";
+    let small = store(&app, text).await.uuid;
+    // Stored compressed, so the range is cut out of the inflated body.
+    let log = "ERROR disk full\n".repeat(10_000);
+    let large = store(&app, &log).await.uuid;
+
+    let load = |uuid: Uuid, range: &str| {
+        Request::builder()
+            .uri(format!("/load/{uuid}"))
+            .header(header::RANGE, range)
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(Body::empty())
+            .unwrap()
+    };
+    for (uuid, body, range, expected) in [
+        (small, text, "bytes=2-4", 2..5),
+        (small, text, "bytes=-3", 7..10),
+        (large, &log, "bytes=160000-", 160000..160000),
+        (large, &log, "bytes=100000-100015", 100000..100016),
+    ] {
+        let response = app.clone().oneshot(load(uuid, range)).await.unwrap();
+        if expected.is_empty() {
+            assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+            assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */160000");
+            continue;
+        }
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT, "{range}");
+        let content_range = format!(
+            "bytes {}-{}/{}",
+            expected.start,
+            expected.end - 1,
+            body.len()
+        );
+        assert_eq!(response.headers()[header::CONTENT_RANGE], content_range);
+        assert!(!response.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(body_bytes(response).await, body[expected].as_bytes());
+    }
+
+    // Several ranges at once are not supported, so the whole body is sent instead.
+    let response = app
+        .clone()
+        .oneshot(load(small, "bytes=0-1,3-4"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::ACCEPT_RANGES], "bytes");
+    assert_eq!(body_bytes(response).await, text.as_bytes());
+}
+
+#[tokio::test]
+async fn test_streamed_upload() {
+    let app = app_with(Limits {
+        max_paste_size: 1000,
+        ..Limits::default()
+    });
+    // Not UTF-8, and sent in chunks without a `Content-Length`.
+    let chunks = [vec![0xff; 300], vec![0x00; 300], vec![0xfe; 300]];
+    let upload = |chunks: Vec<Vec<u8>>| {
+        let stream = futures_util::stream::iter(chunks.into_iter().map(Ok::<_, std::io::Error>));
+        Request::builder()
+            .method(Method::POST)
+            .uri("/store")
+            .body(Body::from_stream(stream))
+            .unwrap()
+    };
+    let response = app.clone().oneshot(upload(chunks.to_vec())).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let StoreResponse { uuid, .. } = serde_json::from_slice(&body_bytes(response).await).unwrap();
+    let response = send(&app, Method::GET, &format!("/load/{uuid}"), "").await;
+    assert_eq!(response.headers()[header::CONTENT_LENGTH], "900");
+    assert_eq!(body_bytes(response).await, chunks.concat());
+
+    let too_large = [chunks.to_vec(), chunks.to_vec()].concat();
+    let response = app.clone().oneshot(upload(too_large)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let response = send(&app, Method::POST, "/store", &"x".repeat(1001)).await;
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    let response = send(&app, Method::GET, "/list", "").await;
+    let list: PasteList = serde_json::from_slice(&body_bytes(response).await).unwrap();
+    assert_eq!(list.total, 1);
+}
+
+#[tokio::test]
+async fn test_healthz() {
+    let limits = Limits::default();
+    let state = AppState::new(Box::<MemoryStorage>::default(), &limits);
+    let app = app::router(state.clone(), &limits);
+    let response = send(&app, Method::GET, "/healthz", "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // While shutting down, load balancers are told to go elsewhere, but requests that still
+    // arrive are served.
+    state.ready.store(false, Ordering::SeqCst);
+    let response = send(&app, Method::GET, "/healthz", "").await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    store(&app, "still served").await;
+}
+
+#[tokio::test]
+async fn test_metrics() {
+    let app = app();
+    store(&app, "counted").await;
+
+    let response = send(&app, Method::GET, "/metrics", "").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers()[header::CONTENT_TYPE]
+        .to_str()
+        .unwrap()
+        .starts_with("text/plain"));
+    let text = String::from_utf8(body_bytes(response).await).unwrap();
+    let store_requests =
+        "pastebin_requests_total{method=\"POST\",route=\"/store\",status=\"200\"} 1\n";
+    assert!(text.contains(store_requests), "{text}");
+    assert!(text.contains("pastebin_store_pastes 1\n"), "{text}");
+    assert!(text.contains("pastebin_store_bytes 7\n"), "{text}");
+}
+
+#[tokio::test]
+async fn test_range_of_burn_after_read() {
+    let app = app();
+    let response = send(&app, Method::POST, "/store?burn=true", "0123456789").await;
+    let StoreResponse { uuid, .. } = serde_json::from_slice(&body_bytes(response).await).unwrap();
+
+    // Only one read is allowed, so it gets everything.
+    let request = Request::builder()
+        .uri(format!("/load/{uuid}"))
+        .header(header::RANGE, "bytes=2-4")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::ACCEPT_RANGES], "none");
+    assert!(!response.headers().contains_key(header::CONTENT_RANGE));
+    assert_eq!(body_bytes(response).await, b"0123456789");
+
+    let response = send(&app, Method::GET, &format!("/load/{uuid}"), "").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}